@@ -1,19 +1,59 @@
+mod alloc_fs;
 mod args;
+mod audit;
+pub(crate) mod copy;
+mod enter;
+mod exec;
+mod forward;
+mod health;
+mod native;
 pub mod opts;
+mod remote;
+mod ssh_config;
 pub mod subs;
-
-use crate::types::{BitteFind, ClusterHandle};
-use anyhow::{anyhow, Context, Result};
+mod template;
+
+use crate::types::{
+    allocs_table,
+    error::Error,
+    known_hosts::{self, KeyUpdate},
+    nodes_table,
+    select::Selector,
+    ssh::SshBackend,
+    AllocQuery, BitteCluster, BitteFind, BitteNode, ClusterHandle, NomadAlloc,
+};
+use alloc_fs::AllocFs;
+use anyhow::{anyhow, bail, Context, Result};
+use audit::Target;
 use clap::{App, ArgMatches, FromArgMatches};
 use clap_generate::{generate, generators};
+use copy::Endpoint;
 use deploy::cli as deployCli;
 use deploy::cli::Opts as ExtDeployOpts;
+use forward::{alloc_forwards, Forward};
+use health::HealthGate;
 use log::*;
-use prettytable::{cell, format, row, Table};
+use prettytable::{cell, format, row, Row, Table};
+use remote::{
+    init_forward, init_ssh, retry_connect, run_parallel, run_sequential, summarize, Backend,
+    OutputMode, Remote, Rollout, RunOpts, CONNECT_FAILED,
+};
 use std::collections::HashMap;
-use std::net::IpAddr;
-use std::{env, io, path::Path, process::Command, process::Stdio, time::Duration};
-use tokio::task::JoinHandle;
+use std::io::{BufRead, Write};
+use std::sync::Arc;
+use std::{env, io, process::Command, process::ExitStatus, process::Stdio, time::Duration};
+
+/// The options that pick the nodes of multi-node runs and deploys, recorded
+/// as needles in the audit log
+const MULTI_NEEDLES: &[&str] = &[
+    "clients",
+    "select",
+    "job-hosts",
+    "namespace",
+    "exclude",
+    "sample",
+    "seed",
+];
 
 pub fn init_log(level: u64) {
     let level = match level {
@@ -28,66 +68,50 @@ pub fn init_log(level: u64) {
 
 pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
     let mut args = sub.values_of_lossy("args").unwrap_or_default();
-    let job: Vec<String> = sub.values_of_t("job").unwrap_or_default();
-    let delay = Duration::from_secs(sub.value_of_t::<u64>("delay").unwrap_or(0));
-
-    let namespace: String = sub.value_of_t("namespace").unwrap_or_default();
-
-    let ip: IpAddr;
-
-    let cluster = cluster.await??;
-
-    if sub.is_present("all") {
-        let nodes = if sub.is_present("clients") {
-            cluster.nodes.find_clients()
-        } else {
-            cluster.nodes
-        };
-
-        let mut iter = nodes.iter().peekable();
-
-        while let Some(node) = iter.next() {
-            init_ssh(node.pub_ip, args.clone(), cluster.name.clone()).await?;
-            if sub.is_present("delay") && iter.peek().is_some() {
-                tokio::time::sleep(delay).await;
-            }
-        }
-
-        return Ok(());
-    } else if sub.is_present("parallel") {
-        let nodes = if sub.is_present("clients") {
-            cluster.nodes.find_clients()
-        } else {
-            cluster.nodes
-        };
+    let job = sub.value_of("job").unwrap_or_default();
+    let mut needles = audit::needles(sub, MULTI_NEEDLES);
+    needles.extend(audit::needles(sub, &["job", "status"]));
 
-        let mut handles: Vec<JoinHandle<Result<()>>> = Vec::with_capacity(nodes.len());
+    let namespace: String = sub.value_of_t("namespace")?;
 
-        for node in nodes.into_iter() {
-            let args = args.clone();
-            let name = cluster.name.clone();
-            let handle = tokio::spawn(async move { init_ssh(node.pub_ip, args, name).await });
-            handles.push(handle);
-        }
+    let mut cluster = cluster.await??;
+    cluster.ssh.connect_timeout = sub
+        .value_of_t("connect-timeout")
+        .ok()
+        .map(Duration::from_secs);
 
-        for handle in handles.into_iter() {
-            handle.await??;
-        }
+    let nodes = match selector(sub)? {
+        Some(selector) => cluster.nodes.clone().find_selected(&selector),
+        None => cluster.nodes.clone(),
+    };
 
-        return Ok(());
+    let node = if sub.is_present("all") || sub.is_present("parallel") {
+        template::check(&args)?;
+        let entry = audit::Entry::new("ssh", &cluster.name, needles, args.clone());
+        return run_multi(sub, cluster, nodes, &namespace, Remote::Ssh(args), entry).await;
     } else if sub.is_present("job") {
-        let (name, group, index) = (&*job[0], &*job[1], &job[2]);
-
-        let nodes = cluster.nodes;
-        let (node, alloc) = nodes.find_with_job(name, group, index, namespace.as_ref())?;
-        if args.is_empty() {
+        let status = Some(
+            sub.value_of_t("status")
+                .unwrap_or_else(|_| "running".into()),
+        );
+        let query = AllocQuery::parse(job, namespace, status)?;
+
+        let (node, alloc) = find_alloc_or_choose(nodes, &query)?;
+        if sub.is_present("enter") {
+            let details = alloc
+                .details(nomad_client(&cluster)?, &cluster.domain)
+                .await?;
+            let task = details.task(sub.value_of("task"))?;
+            let command = enter::command(&details, &alloc, &task, &args)?;
+            args = vec!["-t".into(), command];
+        } else if args.is_empty() {
             args.extend(vec![
                 "-t".into(),
                 format!("cd /var/lib/nomad/alloc/{}; bash", alloc.id),
             ]);
         }
 
-        ip = node.pub_ip;
+        node
     } else {
         let needle = args.first();
 
@@ -98,60 +122,569 @@ pub(crate) async fn ssh(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()>
         let needle = needle.unwrap().clone();
         args = args.drain(1..).collect();
 
-        let nodes = cluster.nodes;
-        let node = nodes.find_needle(&needle)?;
+        let node = find_or_choose(nodes, &needle)?;
+        needles.push(needle);
+        node
+    };
+
+    verify_host_keys(&cluster, std::slice::from_ref(&node)).await?;
+    let entry = audit::Entry::new("ssh", &cluster.name, needles, args.clone());
+    let status = retry_connect(
+        sub.value_of_t("retries").unwrap_or(0),
+        "",
+        |status: &ExitStatus| status.code() == Some(CONNECT_FAILED),
+        || init_ssh(&node, args.clone(), &cluster.ssh),
+    )
+    .await?;
+    entry.finish(vec![Target::new(&node, status.code())], status.code());
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(255));
+    }
+    Ok(())
+}
+
+pub(crate) async fn run(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let path = sub.value_of("script").context("a script is required")?;
+    let args: Vec<String> = sub.values_of_t("args").unwrap_or_default();
+    let namespace: String = sub.value_of_t("namespace")?;
+
+    template::check(&args)?;
+    let script = std::fs::read(path).with_context(|| format!("failed to read script {}", path))?;
+
+    let mut cluster = cluster.await??;
+    cluster.ssh.connect_timeout = sub
+        .value_of_t("connect-timeout")
+        .ok()
+        .map(Duration::from_secs);
+    let nodes = match selector(sub)? {
+        Some(selector) => cluster.nodes.clone().find_selected(&selector),
+        None => cluster.nodes.clone(),
+    };
+
+    let mut command = vec![path.to_owned()];
+    command.extend(args.iter().cloned());
+    let entry = audit::Entry::new(
+        "run",
+        &cluster.name,
+        audit::needles(sub, MULTI_NEEDLES),
+        command,
+    );
+
+    run_multi(
+        sub,
+        cluster,
+        nodes,
+        &namespace,
+        Remote::Script { script, args },
+        entry,
+    )
+    .await
+}
+
+/// Runs `command` on the nodes picked by the '-a' or '-p' flags of `sub`,
+/// shared by `ssh` and `run`
+async fn run_multi(
+    sub: &ArgMatches,
+    cluster: BitteCluster,
+    nodes: Vec<BitteNode>,
+    namespace: &str,
+    command: Remote,
+    entry: audit::Entry,
+) -> Result<()> {
+    let delay = sub.value_of_t("delay").ok().map(Duration::from_secs);
+    let timeout = sub.value_of_t("timeout").ok().map(Duration::from_secs);
+    let backend = sub
+        .value_of("backend")
+        .and_then(|backend| backend.parse().ok())
+        .unwrap_or(SshBackend::Openssh);
+
+    let health_check: Option<Vec<String>> = sub
+        .value_of("health-check")
+        .map(|check| vec![check.to_owned()]);
+    if let Some(check) = &health_check {
+        template::check(check)?;
+    }
+
+    let nodes = multi_nodes(sub, nodes, namespace)?;
+    verify_host_keys(&cluster, &nodes).await?;
+
+    let mode = if sub.is_present("json") {
+        OutputMode::Json
+    } else if sub.is_present("group-output") {
+        OutputMode::Grouped
+    } else if sub.is_present("parallel") || sub.is_present("batch-size") {
+        OutputMode::Prefixed
+    } else {
+        OutputMode::Inherit
+    };
+
+    let gate = if health_check.is_some() || sub.is_present("wait-healthy") {
+        Some(HealthGate {
+            command: health_check,
+            wait_healthy: sub.is_present("wait-healthy"),
+            nomad: cluster.nomad_api_client.clone(),
+            consul_token: sub.value_of("consul-token").map(str::to_owned),
+            domain: cluster.domain.clone(),
+            timeout: Duration::from_secs(sub.value_of_t("health-timeout")?),
+        })
+    } else {
+        None
+    };
+
+    let pool = match backend {
+        SshBackend::Native => Some(Arc::new(native::Pool::new(&cluster.ssh)?)),
+        SshBackend::Openssh => None,
+    };
+
+    let opts = RunOpts {
+        command,
+        ssh: cluster.ssh,
+        backend: pool.clone().map_or(Backend::OpenSsh, Backend::Native),
+        mode,
+        fail_fast: sub.is_present("fail-fast"),
+        timeout,
+        retries: sub.value_of_t("retries").unwrap_or(0),
+    };
+
+    let results = if sub.is_present("parallel") {
+        let concurrency = sub.value_of_t("concurrency").unwrap_or(0);
+        run_parallel(nodes, opts, concurrency).await?
+    } else {
+        let rollout = Rollout {
+            batch_size: sub.value_of_t("batch-size").unwrap_or(1),
+            delay,
+            gate,
+        };
+        run_sequential(nodes, opts, rollout).await?
+    };
+
+    if let Some(pool) = pool {
+        pool.save()?;
+    }
+
+    let targets = results
+        .iter()
+        .map(|result| Target::new(&result.node, result.outcome.code()))
+        .collect();
+    let success = results.iter().all(|result| result.outcome.success());
+    entry.finish(targets, Some(if success { 0 } else { 1 }));
+
+    summarize(&results, mode)
+}
+
+pub(crate) async fn cp(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let mut sources: Vec<Endpoint> = sub.values_of_t("paths")?;
+    let target = sources.pop().context("a destination is required")?;
+    let namespace: String = sub.value_of_t("namespace")?;
+    let status: String = sub.value_of_t("status")?;
+    let recursive = sub.is_present("recursive");
+    let all = sub.is_present("all");
+
+    let cluster = cluster.await??;
+
+    let remote_sources = sources.iter().filter(|source| !source.is_local()).count();
+
+    if remote_sources == 0 && !target.is_local() {
+        let (nodes, target) = target.resolve(cluster.nodes.clone(), &namespace, &status, all)?;
+        verify_host_keys(&cluster, &nodes).await?;
+
+        let sources = sources
+            .into_iter()
+            .filter_map(|source| match source {
+                Endpoint::Local(path) => Some(path),
+                _ => None,
+            })
+            .collect();
+        let upload = Remote::Upload {
+            sources,
+            target,
+            recursive,
+        };
+
+        if nodes.len() == 1 {
+            return copy(upload.command(&nodes[0], &cluster.ssh));
+        }
+
+        let opts = RunOpts {
+            command: upload,
+            ssh: cluster.ssh,
+            backend: Backend::OpenSsh,
+            mode: OutputMode::Prefixed,
+            fail_fast: false,
+            timeout: None,
+            retries: 0,
+        };
+        let results = run_parallel(nodes, opts, 0).await?;
+        return summarize(&results, OutputMode::Prefixed);
+    }
 
-        ip = node.pub_ip;
+    let (source, target) = match (sources.pop(), target) {
+        (Some(source), Endpoint::Local(target)) if sources.is_empty() && remote_sources == 1 => {
+            (source, target)
+        }
+        (_, Endpoint::Local(_)) if remote_sources > 0 => {
+            bail!("only a single path can be copied from a node at a time")
+        }
+        (_, Endpoint::Local(_)) => bail!(
+            "one of the paths has to be on a node, as 'NEEDLE:PATH' or 'JOB/[GROUP[/INDEX]]:PATH'"
+        ),
+        _ => bail!("can't copy between nodes, copy to a local path first"),
     };
 
-    init_ssh(ip, args, cluster.name).await
+    let (nodes, source) = source.resolve(cluster.nodes.clone(), &namespace, &status, false)?;
+    let node = &nodes[0];
+    verify_host_keys(&cluster, &nodes).await?;
+
+    let mut cmd = Command::new("scp");
+    cmd.args(cluster.ssh.scp_args(node));
+    if recursive {
+        cmd.arg("-r");
+    }
+    cmd.arg(cluster.ssh.remote_path(node, &source));
+    cmd.arg(target);
+    copy(cmd)
 }
 
-async fn init_ssh(ip: IpAddr, args: Vec<String>, cluster: String) -> Result<()> {
-    let user_host = &*format!("root@{}", ip);
-    let mut flags = vec!["-x", "-p", "22"];
+pub(crate) async fn forward(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let mut specs: Vec<String> = sub.values_of_t("forwards").unwrap_or_default();
+    let namespace: String = sub.value_of_t("namespace")?;
+
+    let cluster = cluster.await??;
+    let nodes = cluster.nodes.clone();
+
+    let (node, forwards) = if let Some(job) = sub.value_of("job") {
+        let status = sub
+            .value_of_t("status")
+            .unwrap_or_else(|_| "running".into());
+        let query = AllocQuery::parse(job, namespace, Some(status))?;
+        let (node, alloc) = find_alloc_or_choose(nodes, &query)?;
+
+        let details = alloc
+            .details(nomad_client(&cluster)?, &cluster.domain)
+            .await?;
+        let forwards = alloc_forwards(details.ports(), &specs, node.priv_ip)?;
+        (node, forwards)
+    } else {
+        if specs.is_empty() {
+            return Err(anyhow!("first arg must be a host"));
+        }
+        let needle = specs.remove(0);
+        if specs.is_empty() {
+            return Err(anyhow!("at least one port to forward is required"));
+        }
+
+        let forwards = specs
+            .iter()
+            .map(|spec| spec.parse())
+            .collect::<Result<Vec<Forward>>>()?;
+        (find_or_choose(nodes, &needle)?, forwards)
+    };
 
-    let ssh_key_path = format!("secrets/ssh-{}", cluster);
-    let ssh_key = Path::new(&ssh_key_path);
-    if ssh_key.is_file() {
-        flags.push("-i");
-        flags.push(&*ssh_key_path);
+    verify_host_keys(&cluster, std::slice::from_ref(&node)).await?;
+
+    for forward in &forwards {
+        eprintln!("forwarding {}", forward);
     }
+    let specs: Vec<String> = forwards.iter().map(Forward::spec).collect();
 
-    flags.append(&mut vec!["-o", "StrictHostKeyChecking=accept-new"]);
+    let status = init_forward(&node, &specs, &cluster.ssh).await?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(255));
+    }
+    Ok(())
+}
 
-    flags.push(user_host);
+pub(crate) async fn exec(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let command: Vec<String> = sub
+        .values_of_t("command")
+        .unwrap_or_else(|_| vec!["/bin/sh".into()]);
+    let tty = !sub.is_present("no-tty")
+        && atty::is(atty::Stream::Stdin)
+        && atty::is(atty::Stream::Stdout);
 
-    if !args.is_empty() {
-        flags.append(&mut args.iter().map(|string| string.as_str()).collect());
+    let cluster = cluster.await??;
+    let (alloc, task) = find_alloc_task(sub, &cluster).await?;
+
+    let code = exec::exec(
+        &cluster.domain,
+        cluster.nomad_token,
+        &alloc,
+        &task,
+        &command,
+        tty,
+    )
+    .await?;
+    if code != 0 {
+        std::process::exit(code);
     }
-    let ssh_args = flags.into_iter();
+    Ok(())
+}
 
-    let mut cmd = Command::new("ssh");
-    let cmd_with_args = cmd.args(ssh_args);
-    info!("cmd: {:?}", cmd_with_args);
+pub(crate) async fn logs(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+    let (alloc, task) = find_alloc_task(sub, &cluster).await?;
+
+    alloc_fs::logs(
+        nomad_client(&cluster)?,
+        &cluster.domain,
+        &alloc,
+        &task,
+        sub.is_present("stderr"),
+        sub.is_present("follow"),
+    )
+    .await
+}
+
+pub(crate) async fn alloc_fs(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let (command, sub) = sub.subcommand().context("a subcommand is required")?;
+    let mut job: Vec<String> = sub.values_of_t("args")?;
+    let path = job.pop().context("a path is required")?;
+
+    let cluster = cluster.await??;
+    let query = alloc_query(sub, &job)?;
+    let (_, alloc) = find_alloc_or_choose(cluster.nodes.clone(), &query)?;
+    let fs = AllocFs::new(nomad_client(&cluster)?, &cluster.domain, &alloc);
+
+    match command {
+        "ls" => {
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_CLEAN);
+            for entry in fs.ls(&path).await? {
+                let name = if entry.is_dir {
+                    format!("{}/", entry.name)
+                } else {
+                    entry.name
+                };
+                table.add_row(row![entry.mode, r->entry.size, entry.modified, name]);
+            }
+            table.printstd();
+        }
+        "cat" => fs.cat(&path, &mut io::stdout()).await?,
+        "stat" => {
+            let info = fs.stat(&path).await?;
+            let mut table = Table::new();
+            table.set_format(*format::consts::FORMAT_BOX_CHARS);
+            table.add_row(row![b->"Name", info.name]);
+            table.add_row(row![b->"Directory", info.is_dir]);
+            table.add_row(row![b->"Size", info.size]);
+            table.add_row(row![b->"Mode", info.mode]);
+            table.add_row(row![b->"Modified", info.modified]);
+            table.printstd();
+        }
+        "tar" => match sub.value_of("output") {
+            Some(file) => {
+                let file = std::fs::File::create(file)
+                    .with_context(|| format!("failed to create {}", file))?;
+                fs.tar(&path, io::BufWriter::new(file)).await?
+            }
+            None if atty::is(atty::Stream::Stdout) => {
+                bail!("refusing to write a tarball to a terminal, use '--output'")
+            }
+            None => fs.tar(&path, io::stdout()).await?,
+        },
+        _ => unreachable!("unknown alloc-fs command {}", command),
+    }
 
-    cmd.spawn()
-        .with_context(|| "ssh command failed")?
-        .wait()
-        .with_context(|| "ssh command didn't finish?")?;
     Ok(())
 }
 
+fn nomad_client(cluster: &BitteCluster) -> Result<&reqwest::Client> {
+    cluster
+        .nomad_api_client
+        .as_deref()
+        .context("a Nomad token is required to access allocations")
+}
+
+/// Builds a query for <JOB> in '--namespace' with '--status'
+fn alloc_query(sub: &ArgMatches, job: &[String]) -> Result<AllocQuery> {
+    let namespace: String = sub.value_of_t("namespace")?;
+    let status: String = sub.value_of_t("status")?;
+    AllocQuery::new(job, namespace, Some(status))
+}
+
+/// Finds the allocation given by <JOB>, and the task given by '--task' or
+/// the only task in it
+async fn find_alloc_task(sub: &ArgMatches, cluster: &BitteCluster) -> Result<(NomadAlloc, String)> {
+    let job: Vec<String> = sub.values_of_t("job")?;
+    let query = alloc_query(sub, &job)?;
+    let (_, alloc) = find_alloc_or_choose(cluster.nodes.clone(), &query)?;
+
+    let details = alloc
+        .details(nomad_client(cluster)?, &cluster.domain)
+        .await?;
+    let task = details.task(sub.value_of("task"))?;
+
+    Ok((alloc, task))
+}
+
+/// Runs scp in the foreground, exiting with its code if it failed
+fn copy(mut cmd: Command) -> Result<()> {
+    info!("cmd: {:?}", cmd);
+    let status = cmd.status().with_context(|| "scp command failed")?;
+    if !status.success() {
+        std::process::exit(status.code().unwrap_or(255));
+    }
+    Ok(())
+}
+
+/// Makes sure the host keys of `nodes` are known before connecting to them,
+/// reading those of new instances from their console output where possible
+async fn verify_host_keys(cluster: &BitteCluster, nodes: &[BitteNode]) -> Result<()> {
+    if let Some(path) = &cluster.ssh.known_hosts {
+        known_hosts::update(path, &cluster.nodes, nodes, false).await?;
+    }
+    Ok(())
+}
+
+/// Like `find_needle`, but lets the user pick one of several matching nodes
+/// when stdin is a terminal
+fn find_or_choose(nodes: Vec<BitteNode>, needle: &str) -> Result<BitteNode> {
+    match nodes.find_needle(needle) {
+        Err(err) if atty::is(atty::Stream::Stdin) => match err.downcast::<Error>() {
+            Ok(Error::AmbiguousNeedle { mut candidates, .. }) => {
+                let table = nodes_table(&candidates);
+                let choice = choose(table, candidates.len(), needle)?;
+                Ok(candidates.swap_remove(choice))
+            }
+            Ok(err) => Err(err.into()),
+            Err(err) => Err(err),
+        },
+        result => result,
+    }
+}
+
+/// Like `find_with_job`, but lets the user pick one of several matching
+/// allocations when stdin is a terminal
+fn find_alloc_or_choose(
+    nodes: Vec<BitteNode>,
+    query: &AllocQuery,
+) -> Result<(BitteNode, NomadAlloc)> {
+    match nodes.find_with_job(query) {
+        Err(err) if atty::is(atty::Stream::Stdin) => match err.downcast::<Error>() {
+            Ok(Error::AmbiguousAlloc { mut candidates, .. }) => {
+                let table = allocs_table(&candidates);
+                let choice = choose(table, candidates.len(), &query.to_string())?;
+                Ok(candidates.swap_remove(choice))
+            }
+            Ok(err) => Err(err.into()),
+            Err(err) => Err(err),
+        },
+        result => result,
+    }
+}
+
+/// Prints a numbered table of candidates to stderr and reads a choice from stdin
+fn choose(table: Table, count: usize, needle: &str) -> Result<usize> {
+    eprint!("{} matches {} candidates:\n{}", needle, count, table);
+
+    let stdin = io::stdin();
+    loop {
+        eprint!("choose one [1-{}]: ", count);
+        io::stderr().flush()?;
+
+        let mut line = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Err(anyhow!("no candidate chosen for {}", needle));
+        }
+
+        match line.trim().parse::<usize>() {
+            Ok(choice) if (1..=count).contains(&choice) => return Ok(choice - 1),
+            _ => eprintln!("'{}' is not a valid choice", line.trim()),
+        }
+    }
+}
+
+/// Narrows down the nodes targeted by '-a' or '-p'
+fn multi_nodes(sub: &ArgMatches, nodes: Vec<BitteNode>, namespace: &str) -> Result<Vec<BitteNode>> {
+    let nodes = if sub.is_present("clients") {
+        nodes.find_clients()
+    } else {
+        nodes
+    };
+
+    let nodes = match sub.value_of("job-hosts") {
+        Some(spec) => job_hosts(nodes, spec, namespace)?,
+        None => nodes,
+    };
+
+    let exclude: Vec<String> = sub.values_of_t("exclude").unwrap_or_default();
+    let sample = sub.value_of_t("sample").ok();
+    let seed = sub.value_of_t("seed").ok();
+    exclude_and_sample(nodes, &exclude, sample, seed)
+}
+
+fn exclude_and_sample(
+    nodes: Vec<BitteNode>,
+    exclude: &[String],
+    sample: Option<usize>,
+    seed: Option<u64>,
+) -> Result<Vec<BitteNode>> {
+    let nodes = nodes.exclude_needles(exclude.iter().map(AsRef::as_ref).collect())?;
+
+    match sample {
+        Some(count) => {
+            let seed = seed.unwrap_or_else(rand::random);
+            eprintln!(
+                "sampling {} of {} nodes with --seed {}",
+                count.min(nodes.len()),
+                nodes.len(),
+                seed
+            );
+            Ok(nodes.sample(count, seed))
+        }
+        None => Ok(nodes),
+    }
+}
+
+fn job_hosts(nodes: Vec<BitteNode>, spec: &str, namespace: &str) -> Result<Vec<BitteNode>> {
+    let query = AllocQuery::job_hosts(spec, namespace.to_owned());
+    let nodes = nodes.find_job_hosts(&query);
+    if nodes.is_empty() {
+        bail!(
+            "{} has no running allocations in namespace {}",
+            query,
+            namespace
+        );
+    }
+    Ok(nodes)
+}
+
+fn selector(sub: &ArgMatches) -> Result<Option<Selector>> {
+    if sub.is_present("select") {
+        Ok(Some(sub.value_of_t("select")?))
+    } else {
+        Ok(None)
+    }
+}
+
 pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
     let opts = <subs::Deploy as FromArgMatches>::from_arg_matches(sub).unwrap_or_default();
+    let mut needles = audit::needles(sub, MULTI_NEEDLES);
+    needles.extend(opts.nodes.iter().cloned());
     let cluster = cluster.await??;
 
     info!("node needles: {:?}", opts.nodes);
 
+    let nodes = match &opts.select {
+        Some(selector) => cluster.nodes.clone().find_selected(selector),
+        None => cluster.nodes.clone(),
+    };
+
+    let nodes = match &opts.job_hosts {
+        Some(spec) => job_hosts(nodes, spec, &opts.namespace)?,
+        None => nodes,
+    };
+
     let instances = if opts.clients {
-        cluster.nodes.find_clients()
+        nodes.find_clients()
+    } else if opts.nodes.is_empty() && (opts.select.is_some() || opts.job_hosts.is_some()) {
+        nodes
     } else {
-        cluster
-            .nodes
-            .find_needles(opts.nodes.iter().map(AsRef::as_ref).collect())
+        nodes.find_needles(opts.nodes.iter().map(AsRef::as_ref).collect())?
     };
 
+    let instances = exclude_and_sample(instances, &opts.exclude, opts.sample, opts.seed)?;
+
     let nixos_configurations: Vec<String> = instances
         .iter()
         .map(|i| i.nixos.clone())
@@ -180,7 +713,31 @@ pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<(
 
     let targets: Vec<String> = instances
         .iter()
-        .map(|i| format!(".#{}@{}:22", i.nixos, i.pub_ip))
+        .map(|i| format!(".#{}@{}:{}", i.nixos, i.pub_ip, cluster.ssh.port))
+        .collect();
+
+    verify_host_keys(&cluster, &instances).await?;
+
+    // explicit deploy-rs settings win over the cluster's ssh settings
+    let mut generic_settings = opts.generic_settings;
+    if generic_settings.ssh_user.is_none() {
+        generic_settings.ssh_user = Some(cluster.ssh.user.clone());
+    }
+    generic_settings.ssh_opts.extend(cluster.ssh.options());
+    for option in cluster.ssh.shared_host_key_options(&instances)? {
+        generic_settings.ssh_opts.push("-o".to_owned());
+        generic_settings.ssh_opts.push(option);
+    }
+
+    let entry = audit::Entry::new(
+        "deploy",
+        &cluster.name,
+        needles,
+        audit::action_args("deploy"),
+    );
+    let audit_targets: Vec<Target> = instances
+        .iter()
+        .map(|instance| Target::new(instance, None))
         .collect();
 
     info!("redeploy: {:?}", targets);
@@ -190,10 +747,12 @@ pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<(
         target: None,
         targets: Some(targets),
         flags: opts.flags,
-        generic_settings: opts.generic_settings,
+        generic_settings,
     };
     // wait_for_ssh(&instance.pub_ip).await?;
-    if let Err(err) = deployCli::run(Some(opts)).await {
+    let result = deployCli::run(Some(opts)).await;
+    entry.finish(audit_targets, Some(if result.is_ok() { 0 } else { 1 }));
+    if let Err(err) = result {
         error!("{}", err);
         // NB: if your up for a mass rebuild you are expected to:
         //   - Randomly check on a representative single node before
@@ -206,14 +765,146 @@ pub(crate) async fn deploy(sub: &ArgMatches, cluster: ClusterHandle) -> Result<(
     Ok(())
 }
 
+pub(crate) async fn ssh_config(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let cluster = cluster.await??;
+
+    let bastion = match sub.value_of("bastion") {
+        Some(needle) => Some(cluster.nodes.clone().find_needle(needle)?),
+        None => cluster
+            .nodes
+            .iter()
+            .filter(|node| !node.pub_ip.is_unspecified())
+            .find(|node| node.name == "bastion")
+            .or_else(|| {
+                cluster
+                    .nodes
+                    .iter()
+                    .find(|node| node.asg.is_none() && !node.pub_ip.is_unspecified())
+            })
+            .cloned(),
+    };
+
+    let nodes = match selector(sub)? {
+        Some(selector) => cluster.nodes.find_selected(&selector),
+        None => cluster.nodes,
+    };
+
+    let prefix = sub.value_of("prefix").unwrap_or_default();
+    let hosts = ssh_config::render(&nodes, &cluster.ssh, prefix, bastion.as_ref());
+
+    if sub.is_present("include") {
+        let path = ssh_config::include(&cluster.name, &hosts)?;
+        eprintln!("updated {}", path.display());
+    } else {
+        print!("{}", hosts);
+    }
+
+    Ok(())
+}
+
+pub(crate) async fn refresh_host_keys(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
+    let needles: Vec<String> = sub.values_of_t("nodes").unwrap_or_default();
+    let cluster = cluster.await??;
+
+    let path = cluster
+        .ssh
+        .known_hosts
+        .as_ref()
+        .context("no place to keep host keys, HOME is not set")?;
+
+    let nodes = match selector(sub)? {
+        Some(selector) => cluster.nodes.clone().find_selected(&selector),
+        None => cluster.nodes.clone(),
+    };
+    let nodes = if needles.is_empty() {
+        nodes
+    } else {
+        nodes.find_needles(needles.iter().map(AsRef::as_ref).collect())?
+    };
+
+    let updates = known_hosts::update(path, &cluster.nodes, &nodes, true).await?;
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BOX_CHARS);
+    table.add_row(row![ bc => "Name", "Instance ID", "Host Keys"]);
+    for (node, update) in nodes.iter().zip(updates) {
+        let keys = match update {
+            KeyUpdate::Fetched(count) => cell!(format!("{} from console", count)),
+            KeyUpdate::Unverified => cell!(Fy -> "trusted on next connect"),
+            KeyUpdate::Known => cell!("known"),
+        };
+        table.add_row(Row::new(vec![cell!(node.name), cell!(node.id), keys]));
+    }
+    table.printstd();
+
+    Ok(())
+}
+
+pub(crate) fn audit(sub: &ArgMatches, cluster: &str) -> Result<()> {
+    let parse = |id: &str| sub.value_of(id).map(audit::parse_time).transpose();
+    let query = audit::Query {
+        cluster: if sub.is_present("all-clusters") {
+            None
+        } else {
+            Some(cluster.to_owned())
+        },
+        nodes: sub.values_of_t("node").unwrap_or_default(),
+        since: parse("since")?,
+        until: parse("until")?,
+    };
+    let records = audit::read(&query)?;
+
+    if sub.is_present("json") {
+        for record in &records {
+            println!("{}", serde_json::to_string(record)?);
+        }
+        return Ok(());
+    }
+
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BOX_CHARS);
+    table.add_row(
+        row![ bc => "Time", "Cluster", "User", "Action", "Nodes", "Command", "Exit Code", "Duration"],
+    );
+    for record in &records {
+        let nodes = match &record.targets[..] {
+            [target] if target.name.is_empty() => target.id.clone(),
+            [target] => target.name.clone(),
+            targets => format!("{} nodes", targets.len()),
+        };
+        let exit_code = match record.exit_code {
+            _ if !record.finished => cell!(Fy -> "unfinished"),
+            Some(0) => cell!("0"),
+            Some(code) => cell!(Fr -> code),
+            None => cell!(""),
+        };
+        table.add_row(Row::new(vec![
+            cell!(record.timestamp),
+            cell!(record.cluster),
+            cell!(record.user),
+            cell!(record.action),
+            cell!(nodes),
+            cell!(record.command.join(" ")),
+            exit_code,
+            cell!(format!("{:.1}s", record.duration_ms as f64 / 1000.0)),
+        ]));
+    }
+    table.printstd();
+
+    Ok(())
+}
+
 pub(crate) async fn info(sub: &ArgMatches, cluster: ClusterHandle) -> Result<()> {
     let json: bool = sub.is_present("json");
-    info_print(cluster, json).await?;
+    info_print(cluster, json, selector(sub)?).await?;
     Ok(())
 }
 
-async fn info_print(cluster: ClusterHandle, json: bool) -> Result<()> {
-    let cluster = cluster.await??;
+async fn info_print(cluster: ClusterHandle, json: bool, selector: Option<Selector>) -> Result<()> {
+    let mut cluster = cluster.await??;
+    if let Some(selector) = selector {
+        cluster.nodes = cluster.nodes.find_selected(&selector);
+    }
     if json {
         let stdout = io::stdout();
         let handle = stdout.lock();
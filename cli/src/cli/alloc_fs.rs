@@ -0,0 +1,218 @@
+use crate::types::NomadAlloc;
+use anyhow::{bail, Context, Result};
+use log::*;
+use reqwest::{Client, Response};
+use serde::Deserialize;
+use std::io::Write;
+
+/// A frame of the streams returned by Nomad's `/v1/client/fs/*` endpoints
+#[derive(Deserialize)]
+struct StreamFrame {
+    #[serde(rename = "Data")]
+    data: Option<String>,
+    #[serde(rename = "FileEvent")]
+    file_event: Option<String>,
+}
+
+/// Prints the stdout or stderr logs of `task`, and keeps printing new lines
+/// as they are written if `follow` is set
+pub(crate) async fn logs(
+    client: &Client,
+    domain: &str,
+    alloc: &NomadAlloc,
+    task: &str,
+    stderr: bool,
+    follow: bool,
+) -> Result<()> {
+    let url = format!("https://nomad.{}/v1/client/fs/logs/{}", domain, alloc.id);
+    let response = client
+        .get(&url)
+        .query(&[
+            ("namespace", alloc.namespace.as_str()),
+            ("task", task),
+            ("type", if stderr { "stderr" } else { "stdout" }),
+            ("follow", if follow { "true" } else { "false" }),
+            ("origin", "start"),
+            ("offset", "0"),
+        ])
+        .send()
+        .await
+        .with_context(|| format!("failed to query: {}", &url))?
+        .error_for_status()?;
+
+    copy_frames(response, &mut std::io::stdout()).await
+}
+
+/// Decodes the concatenated JSON frames of a streaming response into `out`
+async fn copy_frames(mut response: Response, out: &mut impl Write) -> Result<()> {
+    let mut buffer = Vec::new();
+
+    while let Some(chunk) = response.chunk().await? {
+        buffer.extend_from_slice(&chunk);
+        let consumed = write_frames(&buffer, out)?;
+        buffer.drain(..consumed);
+    }
+
+    Ok(())
+}
+
+/// Writes the data of all complete frames in `buffer`, returning how many
+/// bytes they took up
+fn write_frames(buffer: &[u8], out: &mut impl Write) -> Result<usize> {
+    let mut frames = serde_json::Deserializer::from_slice(buffer).into_iter::<StreamFrame>();
+    let mut consumed = 0;
+
+    loop {
+        match frames.next() {
+            Some(Ok(frame)) => {
+                if let Some(event) = frame.file_event {
+                    info!("log file event: {}", event);
+                }
+                if let Some(data) = frame.data {
+                    out.write_all(&base64::decode(data)?)?;
+                }
+                consumed = frames.byte_offset();
+            }
+            Some(Err(err)) if err.is_eof() => break,
+            Some(Err(err)) => return Err(err).context("failed to decode stream from nomad"),
+            None => break,
+        }
+    }
+
+    out.flush()?;
+    Ok(consumed)
+}
+
+/// An entry of an allocation directory, as returned by `/v1/client/fs/ls`
+#[derive(Deserialize)]
+pub(crate) struct FileInfo {
+    #[serde(rename = "Name")]
+    pub name: String,
+    #[serde(rename = "IsDir")]
+    pub is_dir: bool,
+    #[serde(rename = "Size")]
+    pub size: u64,
+    #[serde(rename = "FileMode")]
+    pub mode: String,
+    #[serde(rename = "ModTime")]
+    pub modified: String,
+}
+
+impl FileInfo {
+    /// The permission bits of `mode`, which looks like `-rw-r--r--`
+    fn permissions(&self) -> u32 {
+        let bits = self.mode.chars().rev().take(9);
+        bits.enumerate()
+            .filter(|(_, c)| *c != '-')
+            .fold(0, |mode, (bit, _)| mode | 1 << bit)
+    }
+
+    fn mtime(&self) -> u64 {
+        chrono::DateTime::parse_from_rfc3339(&self.modified)
+            .map(|time| time.timestamp().max(0) as u64)
+            .unwrap_or_default()
+    }
+}
+
+/// The files of an allocation, as seen through Nomad's `/v1/client/fs` API
+pub(crate) struct AllocFs<'a> {
+    client: &'a Client,
+    domain: &'a str,
+    alloc: &'a NomadAlloc,
+}
+
+impl<'a> AllocFs<'a> {
+    pub fn new(client: &'a Client, domain: &'a str, alloc: &'a NomadAlloc) -> Self {
+        Self {
+            client,
+            domain,
+            alloc,
+        }
+    }
+
+    async fn get(&self, endpoint: &str, path: &str) -> Result<Response> {
+        let url = format!(
+            "https://nomad.{}/v1/client/fs/{}/{}",
+            self.domain, endpoint, self.alloc.id
+        );
+        let response = self
+            .client
+            .get(&url)
+            .query(&[("namespace", self.alloc.namespace.as_str()), ("path", path)])
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", &url))?;
+
+        let status = response.status();
+        if !status.is_success() {
+            let reason = response.text().await.unwrap_or_default();
+            bail!("{}: {} {}", path, status, reason.trim());
+        }
+        Ok(response)
+    }
+
+    pub async fn ls(&self, path: &str) -> Result<Vec<FileInfo>> {
+        let mut entries: Vec<FileInfo> = self
+            .get("ls", path)
+            .await?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode listing of {}", path))?;
+        entries.sort_by(|a, b| a.name.cmp(&b.name));
+        Ok(entries)
+    }
+
+    pub async fn stat(&self, path: &str) -> Result<FileInfo> {
+        self.get("stat", path)
+            .await?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode stat of {}", path))
+    }
+
+    pub async fn cat(&self, path: &str, out: &mut impl Write) -> Result<()> {
+        let mut response = self.get("cat", path).await?;
+        while let Some(chunk) = response.chunk().await? {
+            out.write_all(&chunk)?;
+        }
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Writes `path` and everything below it to `out` as a tarball
+    pub async fn tar(&self, path: &str, out: impl Write) -> Result<()> {
+        let mut builder = tar::Builder::new(out);
+        let root = self.stat(path).await?;
+        let name = match root.name.as_str() {
+            "" | "/" => "alloc".to_owned(),
+            name => name.to_owned(),
+        };
+
+        let mut pending = vec![(path.trim_end_matches('/').to_owned(), name, root)];
+        while let Some((path, name, info)) = pending.pop() {
+            let mut header = tar::Header::new_gnu();
+            header.set_mode(info.permissions());
+            header.set_mtime(info.mtime());
+
+            if info.is_dir {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                builder.append_data(&mut header, &name, std::io::empty())?;
+
+                for entry in self.ls(&path).await? {
+                    let path = format!("{}/{}", path, entry.name);
+                    let name = format!("{}/{}", name, entry.name);
+                    pending.push((path, name, entry));
+                }
+            } else {
+                let mut data = Vec::with_capacity(info.size as usize);
+                self.cat(&path, &mut data).await?;
+                header.set_size(data.len() as u64);
+                builder.append_data(&mut header, &name, &data[..])?;
+            }
+        }
+
+        builder.into_inner()?.flush()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use crate::types::BitteNode;
+use crate::utils::data_dir;
+use anyhow::{bail, Context, Result};
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use clap::ArgMatches;
+use log::*;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufRead, BufReader, Write};
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::time::Instant;
+
+/// Options whose values are never written to the audit log
+const SECRET_OPTIONS: [&str; 2] = ["--nomad", "--consul-token"];
+
+/// A line of the audit log: who ran what against which nodes of a cluster
+///
+/// Each action is written once as it starts and again once it is done, both
+/// with the same `id`; an action that never got to the second record, like
+/// one cut short by Ctrl-C, is still running or was interrupted.
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Record {
+    /// tells the records of one action from those of others
+    pub id: String,
+    /// when the action started, in RFC 3339
+    pub timestamp: String,
+    pub cluster: String,
+    pub user: String,
+    /// the subcommand, e.g. `ssh` or `deploy`
+    pub action: String,
+    /// the needles and options that picked the nodes
+    pub needles: Vec<String>,
+    pub targets: Vec<Target>,
+    /// what ran on the nodes: ssh arguments, a script and its arguments, or
+    /// the flags of a deploy
+    pub command: Vec<String>,
+    pub exit_code: Option<i32>,
+    pub duration_ms: u128,
+    pub finished: bool,
+}
+
+#[derive(Serialize, Deserialize)]
+pub(crate) struct Target {
+    pub id: String,
+    pub name: String,
+    pub priv_ip: IpAddr,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+}
+
+impl Target {
+    pub fn new(node: &BitteNode, exit_code: Option<i32>) -> Self {
+        Self {
+            id: node.id.clone(),
+            name: node.name.clone(),
+            priv_ip: node.priv_ip,
+            exit_code,
+        }
+    }
+
+    /// Whether `needle` is the id, name or ip of this node, or a prefix of
+    /// its id or name
+    fn matches(&self, needle: &str) -> bool {
+        self.priv_ip.to_string() == needle
+            || self.id.starts_with(needle)
+            || self.name.starts_with(needle)
+    }
+}
+
+/// An action being audited, written to the log as it starts and once it is
+/// done; if it is dropped before `finish`, because the action failed, it is
+/// written as done without an exit code
+pub(crate) struct Entry {
+    id: String,
+    started: DateTime<Utc>,
+    start: Instant,
+    cluster: String,
+    action: String,
+    needles: Vec<String>,
+    command: Vec<String>,
+    finished: bool,
+}
+
+impl Entry {
+    pub fn new(action: &str, cluster: &str, needles: Vec<String>, command: Vec<String>) -> Self {
+        let started = Utc::now();
+        let entry = Self {
+            id: format!("{}-{}", std::process::id(), started.timestamp_nanos()),
+            started,
+            start: Instant::now(),
+            cluster: cluster.to_owned(),
+            action: action.to_owned(),
+            needles,
+            command,
+            finished: false,
+        };
+        entry.write(Vec::new(), None);
+        entry
+    }
+
+    /// Appends the final record of this action to the audit log
+    pub fn finish(mut self, targets: Vec<Target>, exit_code: Option<i32>) {
+        self.finished = true;
+        self.write(targets, exit_code);
+    }
+
+    /// Failing to write the audit log is only a warning, the action happens
+    /// either way
+    fn write(&self, targets: Vec<Target>, exit_code: Option<i32>) {
+        let record = Record {
+            id: self.id.clone(),
+            timestamp: self.started.to_rfc3339(),
+            cluster: self.cluster.clone(),
+            user: current_user(),
+            action: self.action.clone(),
+            needles: self.needles.clone(),
+            targets,
+            command: self.command.clone(),
+            exit_code,
+            duration_ms: self.start.elapsed().as_millis(),
+            finished: self.finished,
+        };
+
+        if let Err(err) = append(&record) {
+            warn!("failed to write audit log: {:#}", err);
+        }
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        if !self.finished {
+            self.finished = true;
+            self.write(Vec::new(), None);
+        }
+    }
+}
+
+/// `$XDG_DATA_HOME/bitte/audit.jsonl`, falling back to `~/.local/share`
+fn log_path() -> Result<PathBuf> {
+    Ok(data_dir()
+        .context("no place to keep the audit log, HOME is not set")?
+        .join("audit.jsonl"))
+}
+
+fn append(record: &Record) -> Result<()> {
+    let path = log_path()?;
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+    }
+
+    let mut line = serde_json::to_string(record)?;
+    line.push('\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(&path)
+        .and_then(|mut file| file.write_all(line.as_bytes()))
+        .with_context(|| format!("failed to write {}", path.display()))
+}
+
+fn current_user() -> String {
+    env::var("USER")
+        .or_else(|_| env::var("LOGNAME"))
+        .unwrap_or_else(|_| "unknown".to_owned())
+}
+
+/// The given options of `sub` among `ids`, as `--id value`, for the
+/// `needles` of a record
+pub(crate) fn needles(sub: &ArgMatches, ids: &[&str]) -> Vec<String> {
+    let mut needles = Vec::new();
+    for id in ids {
+        if sub.occurrences_of(id) == 0 {
+            continue;
+        }
+        match sub.values_of(id) {
+            Some(values) => {
+                for value in values {
+                    needles.push(format!("--{} {}", id, value));
+                }
+            }
+            None => needles.push(format!("--{}", id)),
+        }
+    }
+    needles
+}
+
+/// The arguments given after `action` on the command line, with the values
+/// of secret options left out
+pub(crate) fn action_args(action: &str) -> Vec<String> {
+    redact_args(env::args(), action)
+}
+
+fn redact_args(args: impl IntoIterator<Item = String>, action: &str) -> Vec<String> {
+    let mut args = args.into_iter().skip_while(|arg| arg != action).skip(1);
+    let mut redacted = Vec::new();
+
+    while let Some(arg) = args.next() {
+        match arg.split_once('=') {
+            Some((option, _)) if SECRET_OPTIONS.contains(&option) => {
+                redacted.push(format!("{}=<redacted>", option));
+            }
+            _ if SECRET_OPTIONS.contains(&arg.as_str()) => {
+                args.next();
+                redacted.push(arg);
+                redacted.push("<redacted>".to_owned());
+            }
+            _ => redacted.push(arg),
+        }
+    }
+
+    redacted
+}
+
+/// Which records `bitte audit` shows
+pub(crate) struct Query {
+    /// `None` for all clusters
+    pub cluster: Option<String>,
+    pub nodes: Vec<String>,
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+}
+
+impl Query {
+    fn matches(&self, record: &Record, time: DateTime<Utc>) -> bool {
+        self.cluster
+            .as_ref()
+            .map_or(true, |cluster| *cluster == record.cluster)
+            && self.since.map_or(true, |since| time >= since)
+            && self.until.map_or(true, |until| time <= until)
+            && (self.nodes.is_empty()
+                || self
+                    .nodes
+                    .iter()
+                    .any(|needle| record.targets.iter().any(|target| target.matches(needle))))
+    }
+}
+
+/// Reads the records of the audit log matching `query`, oldest first, with
+/// the final record of each action in place of the one written as it started
+pub(crate) fn read(query: &Query) -> Result<Vec<Record>> {
+    let path = log_path()?;
+    let file = match std::fs::File::open(&path) {
+        Ok(file) => file,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+
+    let mut records: Vec<Record> = Vec::new();
+    let mut started: HashMap<String, usize> = HashMap::new();
+    for (number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let record: Record = match serde_json::from_str(&line) {
+            Ok(record) => record,
+            Err(err) => {
+                warn!("{}:{}: {}", path.display(), number + 1, err);
+                continue;
+            }
+        };
+        let time = match DateTime::parse_from_rfc3339(&record.timestamp) {
+            Ok(time) => time.with_timezone(&Utc),
+            Err(_) => continue,
+        };
+        if !query.matches(&record, time) {
+            continue;
+        }
+        match started.get(&record.id) {
+            Some(&index) => records[index] = record,
+            None => {
+                started.insert(record.id.clone(), records.len());
+                records.push(record);
+            }
+        }
+    }
+
+    Ok(records)
+}
+
+/// Parses an RFC 3339 time, a `YYYY-MM-DD` date, or a time ago like `30m`,
+/// `12h` or `7d`
+pub(crate) fn parse_time(time: &str) -> Result<DateTime<Utc>> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(time) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(time, "%Y-%m-%d") {
+        return Ok(DateTime::from_utc(date.and_hms(0, 0, 0), Utc));
+    }
+
+    let unit = time.trim_start_matches(|c: char| c.is_ascii_digit());
+    let count: i64 = match time[..time.len() - unit.len()].parse() {
+        Ok(count) => count,
+        Err(_) => bail!(
+            "'{}' is not a time, use e.g. '2021-12-24T18:00:00Z', '2021-12-24' or '12h'",
+            time
+        ),
+    };
+    let ago = match unit {
+        "s" => Duration::seconds(count),
+        "m" => Duration::minutes(count),
+        "h" => Duration::hours(count),
+        "d" => Duration::days(count),
+        "w" => Duration::weeks(count),
+        _ => bail!("'{}' is not a unit of time, use s, m, h, d or w", unit),
+    };
+    Ok(Utc::now() - ago)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn record(cluster: &str, targets: Vec<Target>) -> Record {
+        Record {
+            id: "4242-0".to_owned(),
+            timestamp: "2021-12-24T18:00:00+00:00".to_owned(),
+            cluster: cluster.to_owned(),
+            user: "alice".to_owned(),
+            action: "ssh".to_owned(),
+            needles: Vec::new(),
+            targets,
+            command: Vec::new(),
+            exit_code: Some(0),
+            duration_ms: 0,
+            finished: true,
+        }
+    }
+
+    fn query(cluster: Option<&str>, nodes: &[&str]) -> Query {
+        Query {
+            cluster: cluster.map(str::to_owned),
+            nodes: args(nodes),
+            since: None,
+            until: None,
+        }
+    }
+
+    #[test]
+    fn redacts_secret_options() {
+        let redacted = redact_args(
+            args(&[
+                "bitte",
+                "--cluster",
+                "mantis",
+                "deploy",
+                "--nomad",
+                "6f1e2c3a",
+                "--consul-token=0b4d4e5f",
+                "core-1",
+                "--nomad=8a9b0c1d",
+                "--consul-token",
+                "2e3f4a5b",
+            ]),
+            "deploy",
+        );
+        assert_eq!(
+            redacted,
+            args(&[
+                "--nomad",
+                "<redacted>",
+                "--consul-token=<redacted>",
+                "core-1",
+                "--nomad=<redacted>",
+                "--consul-token",
+                "<redacted>",
+            ])
+        );
+    }
+
+    #[test]
+    fn parses_absolute_times() {
+        let evening = Utc.ymd(2021, 12, 24).and_hms(17, 0, 0);
+        assert_eq!(parse_time("2021-12-24T18:00:00+01:00").unwrap(), evening);
+        assert_eq!(
+            parse_time("2021-12-24").unwrap(),
+            Utc.ymd(2021, 12, 24).and_hms(0, 0, 0)
+        );
+    }
+
+    #[test]
+    fn parses_relative_times() {
+        let about = |time: &str, ago: Duration| {
+            let off = Utc::now() - ago - parse_time(time).unwrap();
+            off.num_seconds().abs() < 60
+        };
+        assert!(about("12h", Duration::hours(12)));
+        assert!(about("2w", Duration::weeks(2)));
+        assert!(about("90s", Duration::seconds(90)));
+
+        for time in ["", "12", "h", "12y", "yesterday", "2021-13-01"] {
+            assert!(parse_time(time).is_err(), "{}", time);
+        }
+    }
+
+    #[test]
+    fn matches_records_by_cluster_node_and_time() {
+        let node = BitteNode::test("i-0abc123", "client-1", "10.0.96.5");
+        let record = record("mantis", vec![Target::new(&node, Some(0))]);
+        let time = Utc.ymd(2021, 12, 24).and_hms(18, 0, 0);
+
+        assert!(query(None, &[]).matches(&record, time));
+        assert!(query(Some("mantis"), &[]).matches(&record, time));
+        assert!(!query(Some("testnet"), &[]).matches(&record, time));
+
+        for needle in ["i-0abc", "client", "10.0.96.5"] {
+            assert!(query(None, &[needle]).matches(&record, time), "{}", needle);
+        }
+        assert!(query(None, &["core-1", "client-1"]).matches(&record, time));
+        assert!(!query(None, &["10.0.96"]).matches(&record, time));
+        assert!(!query(None, &["core-1"]).matches(&record, time));
+
+        let since = |since: DateTime<Utc>| Query {
+            since: Some(since),
+            ..query(None, &[])
+        };
+        assert!(since(time).matches(&record, time));
+        assert!(!since(time + Duration::seconds(1)).matches(&record, time));
+        let until = Query {
+            until: Some(time - Duration::seconds(1)),
+            ..query(None, &[])
+        };
+        assert!(!until.matches(&record, time));
+    }
+}
@@ -0,0 +1,141 @@
+use super::{find_alloc_or_choose, find_or_choose};
+use crate::types::{AllocQuery, BitteFind, BitteNode};
+use anyhow::{bail, Result};
+use ipnet::IpNet;
+use std::str::FromStr;
+
+/// One side of `bitte cp`
+#[derive(Debug, PartialEq)]
+pub(crate) enum Endpoint {
+    /// a path on this machine
+    Local(String),
+    /// `NEEDLE:PATH`, a path on a node
+    Node { needle: String, path: String },
+    /// `JOB/[GROUP[/INDEX]]:PATH` or `ALLOC_ID/:PATH`, a path in an
+    /// allocation directory
+    Alloc { query: Vec<String>, path: String },
+}
+
+impl Endpoint {
+    pub fn is_local(&self) -> bool {
+        matches!(self, Self::Local(_))
+    }
+
+    pub fn needs_nomad(&self) -> bool {
+        matches!(self, Self::Alloc { .. })
+    }
+
+    /// Finds the nodes this endpoint refers to, and the path on them; only
+    /// node needles with `all` may resolve to more than one node
+    pub fn resolve(
+        self,
+        nodes: Vec<BitteNode>,
+        namespace: &str,
+        status: &str,
+        all: bool,
+    ) -> Result<(Vec<BitteNode>, String)> {
+        match self {
+            Self::Local(path) => Ok((Vec::new(), path)),
+            Self::Node { needle, path } if all => Ok((nodes.find_needles(vec![&needle])?, path)),
+            Self::Node { needle, path } => Ok((vec![find_or_choose(nodes, &needle)?], path)),
+            Self::Alloc { query, path } => {
+                let query = AllocQuery::new(&query, namespace.to_owned(), Some(status.to_owned()))?;
+                let (node, alloc) = find_alloc_or_choose(nodes, &query)?;
+                let path = format!(
+                    "/var/lib/nomad/alloc/{}/{}",
+                    alloc.id,
+                    path.trim_start_matches('/')
+                );
+                Ok((vec![node], path))
+            }
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = anyhow::Error;
+
+    fn from_str(endpoint: &str) -> Result<Self> {
+        let (host, path) = match endpoint.split_once(':') {
+            Some((host, path)) if !host.is_empty() => (host, path.to_owned()),
+            _ => return Ok(Self::Local(endpoint.to_owned())),
+        };
+
+        // a '/' is what tells allocations from nodes, so a bare job or alloc
+        // id takes a trailing one; CIDR ranges and regexes are still needles
+        let endpoint = if host.starts_with('/') || host.starts_with('.') {
+            Self::Local(endpoint.to_owned())
+        } else if host.starts_with('~') || host.parse::<IpNet>().is_ok() || !host.contains('/') {
+            Self::Node {
+                needle: host.to_owned(),
+                path,
+            }
+        } else {
+            let query: Vec<String> = host
+                .strip_suffix('/')
+                .unwrap_or(host)
+                .split('/')
+                .map(str::to_owned)
+                .collect();
+            if query.len() > 3 || query.iter().any(String::is_empty) {
+                bail!("'{}' is neither JOB/[GROUP[/INDEX]] nor ALLOC_ID/", host);
+            }
+            Self::Alloc { query, path }
+        };
+
+        Ok(endpoint)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(path: &str) -> Endpoint {
+        Endpoint::Local(path.to_owned())
+    }
+
+    fn node(needle: &str, path: &str) -> Endpoint {
+        Endpoint::Node {
+            needle: needle.to_owned(),
+            path: path.to_owned(),
+        }
+    }
+
+    fn alloc(query: &[&str], path: &str) -> Endpoint {
+        Endpoint::Alloc {
+            query: query.iter().map(|part| part.to_string()).collect(),
+            path: path.to_owned(),
+        }
+    }
+
+    #[test]
+    fn tells_allocations_from_nodes_by_a_slash() {
+        let cases = [
+            ("notes.txt", local("notes.txt")),
+            ("./a:b", local("./a:b")),
+            ("/tmp/a:b", local("/tmp/a:b")),
+            (":notes.txt", local(":notes.txt")),
+            ("core-1:/etc/hosts", node("core-1", "/etc/hosts")),
+            ("core-*:", node("core-*", "")),
+            ("10.0.0.0/16:/tmp", node("10.0.0.0/16", "/tmp")),
+            ("~^client-.*/2$:/tmp", node("~^client-.*/2$", "/tmp")),
+            ("mantis/:/local", alloc(&["mantis"], "/local")),
+            ("mantis/miner:/local", alloc(&["mantis", "miner"], "/local")),
+            ("mantis/miner/0:/local", alloc(&["mantis", "miner", "0"], "/local")),
+            ("mantis/miner/:x", alloc(&["mantis", "miner"], "x")),
+            ("5d0a6f3e/:alloc/logs", alloc(&["5d0a6f3e"], "alloc/logs")),
+        ];
+
+        for (input, endpoint) in cases {
+            assert_eq!(input.parse::<Endpoint>().unwrap(), endpoint, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_allocations() {
+        for input in ["mantis/miner/0/1:/tmp", "mantis//0:/tmp", "mantis/miner//:/tmp"] {
+            assert!(input.parse::<Endpoint>().is_err(), "{}", input);
+        }
+    }
+}
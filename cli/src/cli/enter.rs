@@ -0,0 +1,49 @@
+use super::remote::shell_quote;
+use crate::types::{AllocDetails, NomadAlloc};
+use crate::utils::nomad::Driver;
+use anyhow::{anyhow, Result};
+
+/// What runs in the task when no command is given
+const SHELL: &str = "/bin/sh";
+
+/// The command run on the node of `alloc` to get into the container of `task`
+/// with `args`, or a shell if there are none: `docker exec` for docker tasks,
+/// `nsenter` into the namespaces of the task's process for exec tasks
+pub(crate) fn command(
+    details: &AllocDetails,
+    alloc: &NomadAlloc,
+    task: &str,
+    args: &[String],
+) -> Result<String> {
+    let command = if args.is_empty() {
+        SHELL.to_owned()
+    } else {
+        args.join(" ")
+    };
+
+    match details.driver(task) {
+        Some(Driver::Docker) => Ok(format!(
+            "docker exec -it {} {}",
+            shell_quote(&format!("{}-{}", task, alloc.id)),
+            command
+        )),
+        Some(Driver::Exec) => Ok(format!(
+            "pid=; for procs in /sys/fs/cgroup/*/nomad/{alloc}-{task}/cgroup.procs \
+             /sys/fs/cgroup/nomad.slice/{alloc}.{task}.scope/cgroup.procs \
+             /sys/fs/cgroup/nomad.slice/*/{alloc}.{task}.scope/cgroup.procs; do \
+             [ -r \"$procs\" ] && pid=$(head -n 1 \"$procs\") && [ -n \"$pid\" ] && break; \
+             done; \
+             [ -n \"$pid\" ] || {{ echo 'no process of task {name} found' >&2; exit 1; }}; \
+             exec nsenter -t \"$pid\" -a {command}",
+            alloc = alloc.id,
+            task = shell_quote(task),
+            name = task.replace('\'', ""),
+            command = command,
+        )),
+        None => Err(anyhow!(
+            "task '{}' uses the {} driver, only docker and exec tasks can be entered",
+            task,
+            details.driver_name(task).unwrap_or("unknown")
+        )),
+    }
+}
@@ -0,0 +1,185 @@
+use crate::types::NomadAlloc;
+use anyhow::{anyhow, Context, Result};
+use crossterm::terminal;
+use futures_util::{SinkExt, StreamExt};
+use log::*;
+use reqwest::{header::HeaderValue, Url};
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+use std::thread;
+use tokio::sync::mpsc;
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, Message};
+use uuid::Uuid;
+
+/// A frame sent to Nomad's exec endpoint
+#[derive(Serialize)]
+#[serde(rename_all = "snake_case")]
+enum Input {
+    Stdin(Data),
+    TtySize { height: u16, width: u16 },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Data {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<String>,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    close: bool,
+}
+
+/// A frame received from Nomad's exec endpoint
+#[derive(Deserialize)]
+struct Output {
+    stdout: Option<Data>,
+    stderr: Option<Data>,
+    #[serde(default)]
+    exited: bool,
+    result: Option<ExitResult>,
+}
+
+#[derive(Deserialize)]
+struct ExitResult {
+    #[serde(default)]
+    exit_code: i32,
+}
+
+/// Puts the terminal into raw mode for as long as it lives
+struct RawMode;
+
+impl RawMode {
+    fn enable() -> Result<Self> {
+        terminal::enable_raw_mode().with_context(|| "failed to put the terminal in raw mode")?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawMode {
+    fn drop(&mut self) {
+        terminal::disable_raw_mode().ok();
+    }
+}
+
+/// Runs `command` in `task` of `alloc` through Nomad's exec websocket,
+/// returning its exit code
+pub(crate) async fn exec(
+    domain: &str,
+    token: Option<Uuid>,
+    alloc: &NomadAlloc,
+    task: &str,
+    command: &[String],
+    tty: bool,
+) -> Result<i32> {
+    let url = Url::parse_with_params(
+        &format!(
+            "wss://nomad.{}/v1/client/allocation/{}/exec",
+            domain, alloc.id
+        ),
+        &[
+            ("namespace", alloc.namespace.as_str()),
+            ("task", task),
+            ("tty", if tty { "true" } else { "false" }),
+            ("command", &serde_json::to_string(command)?),
+        ],
+    )?;
+    info!("exec: {}", url);
+
+    let mut request = url.as_str().into_client_request()?;
+    if let Some(token) = token {
+        let mut token = HeaderValue::from_str(&token.to_string())?;
+        token.set_sensitive(true);
+        request.headers_mut().insert("X-Nomad-Token", token);
+    }
+
+    let (socket, _) = tokio_tungstenite::connect_async(request)
+        .await
+        .with_context(|| format!("failed to connect to nomad.{}", domain))?;
+    let (mut sink, mut stream) = socket.split();
+
+    let _raw_mode = if tty { Some(RawMode::enable()?) } else { None };
+
+    let (input, mut inputs) = mpsc::unbounded_channel::<Input>();
+    tokio::spawn(async move {
+        while let Some(input) = inputs.recv().await {
+            let frame = match serde_json::to_string(&input) {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+            if sink.send(Message::Text(frame)).await.is_err() {
+                break;
+            }
+        }
+    });
+
+    if tty {
+        let (width, height) = terminal::size().unwrap_or((80, 24));
+        input.send(Input::TtySize { height, width }).ok();
+        tokio::spawn(forward_resizes(input.clone()));
+    }
+    forward_stdin(input);
+
+    while let Some(message) = stream.next().await {
+        let frame = match message? {
+            Message::Text(frame) => frame,
+            Message::Close(_) => break,
+            _ => continue,
+        };
+        let output: Output = serde_json::from_str(&frame)
+            .with_context(|| format!("unexpected message from nomad: {}", frame))?;
+
+        if let Some(data) = output.stdout.and_then(|stdout| stdout.data) {
+            let mut stdout = std::io::stdout();
+            stdout.write_all(&base64::decode(data)?)?;
+            stdout.flush()?;
+        }
+        if let Some(data) = output.stderr.and_then(|stderr| stderr.data) {
+            let mut stderr = std::io::stderr();
+            stderr.write_all(&base64::decode(data)?)?;
+            stderr.flush()?;
+        }
+        if output.exited {
+            return Ok(output.result.map(|result| result.exit_code).unwrap_or(0));
+        }
+    }
+
+    Err(anyhow!("connection closed before the command exited"))
+}
+
+/// Sends stdin to Nomad from a thread of its own; a blocking read can't be
+/// cancelled, so the thread is left behind rather than keeping the runtime
+/// from shutting down once the command has exited
+fn forward_stdin(input: mpsc::UnboundedSender<Input>) {
+    thread::spawn(move || {
+        let mut stdin = std::io::stdin();
+        let mut buffer = vec![0; 4096];
+
+        while let Ok(read) = stdin.read(&mut buffer) {
+            let data = match read {
+                0 => Data {
+                    data: None,
+                    close: true,
+                },
+                read => Data {
+                    data: Some(base64::encode(&buffer[..read])),
+                    close: false,
+                },
+            };
+            if input.send(Input::Stdin(data)).is_err() || read == 0 {
+                break;
+            }
+        }
+    });
+}
+
+/// Tells Nomad about the new size of the terminal whenever it is resized
+async fn forward_resizes(input: mpsc::UnboundedSender<Input>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut resized = signal(SignalKind::window_change())?;
+    while resized.recv().await.is_some() {
+        let (width, height) = terminal::size()?;
+        if input.send(Input::TtySize { height, width }).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,168 @@
+use crate::utils::nomad::Port;
+use anyhow::{bail, Context, Result};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A local port forwarded to a port reachable from a node
+pub(crate) struct Forward {
+    pub local: u16,
+    pub host: String,
+    pub port: u16,
+    /// the label of the allocation port, if forwarding one
+    pub label: Option<String>,
+}
+
+impl Forward {
+    /// The argument for ssh's `-L`
+    pub fn spec(&self) -> String {
+        format!("{}:{}:{}", self.local, self.host, self.port)
+    }
+}
+
+impl FromStr for Forward {
+    type Err = anyhow::Error;
+
+    /// Parses `PORT`, `LOCAL:PORT` or `LOCAL:HOST:PORT`
+    fn from_str(spec: &str) -> Result<Self> {
+        let parts: Vec<&str> = spec.split(':').collect();
+        let (local, host, port) = match parts[..] {
+            [port] => (port, "localhost", port),
+            [local, port] => (local, "localhost", port),
+            [local, host, port] => (local, host, port),
+            _ => bail!(
+                "'{}' is not a valid forward, use PORT, LOCAL:PORT or LOCAL:HOST:PORT",
+                spec
+            ),
+        };
+
+        Ok(Self {
+            local: parse_port(local)?,
+            host: host.to_owned(),
+            port: parse_port(port)?,
+            label: None,
+        })
+    }
+}
+
+impl Display for Forward {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "localhost:{} -> {}:{}", self.local, self.host, self.port)?;
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_port(port: &str) -> Result<u16> {
+    port.parse()
+        .with_context(|| format!("'{}' is not a valid port", port))
+}
+
+/// Forwards to the allocation `ports` named by `LABEL[:LOCAL]` specs, or to
+/// all of them on the same local port if there are none
+pub(crate) fn alloc_forwards(
+    ports: &[Port],
+    specs: &[String],
+    node: IpAddr,
+) -> Result<Vec<Forward>> {
+    let forward = |port: &Port, local: Option<u16>| -> Result<Forward> {
+        let remote = u16::try_from(port.value)
+            .with_context(|| format!("port {} of '{}' is out of range", port.value, port.label))?;
+        let host = match port.host_ip.as_deref() {
+            Some(ip) if !ip.is_empty() => ip.to_owned(),
+            _ => node.to_string(),
+        };
+        Ok(Forward {
+            local: local.unwrap_or(remote),
+            host,
+            port: remote,
+            label: Some(port.label.clone()),
+        })
+    };
+
+    if specs.is_empty() {
+        if ports.is_empty() {
+            bail!("the allocation has no ports to forward");
+        }
+        return ports.iter().map(|port| forward(port, None)).collect();
+    }
+
+    specs
+        .iter()
+        .map(|spec| {
+            let (label, local) = match spec.split_once(':') {
+                Some((label, local)) => (label, Some(parse_port(local)?)),
+                None => (spec.as_str(), None),
+            };
+            match ports.iter().find(|port| port.label == label) {
+                Some(port) => forward(port, local),
+                None => {
+                    let labels: Vec<&str> = ports.iter().map(|port| port.label.as_str()).collect();
+                    bail!(
+                        "the allocation has no port '{}', only: {}",
+                        label,
+                        labels.join(", ")
+                    )
+                }
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn port(label: &str, value: i64, host_ip: Option<&str>) -> Port {
+        Port {
+            label: label.to_owned(),
+            value,
+            to: 0,
+            host_network: None,
+            host_ip: host_ip.map(str::to_owned),
+        }
+    }
+
+    #[test]
+    fn parses_forward_specs() {
+        let cases = [
+            ("8080", "8080:localhost:8080"),
+            ("9000:8080", "9000:localhost:8080"),
+            ("9000:10.24.0.5:8080", "9000:10.24.0.5:8080"),
+        ];
+
+        for (input, spec) in cases {
+            assert_eq!(input.parse::<Forward>().unwrap().spec(), spec, "{}", input);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_forward_specs() {
+        for input in ["", "http", "9000:", ":8080", "70000", "1:host:2:3", "9000:host:"] {
+            assert!(input.parse::<Forward>().is_err(), "{}", input);
+        }
+    }
+
+    #[test]
+    fn forwards_allocation_ports_by_label() {
+        let node: IpAddr = "10.24.0.5".parse().unwrap();
+        let ports = [port("rpc", 8546, None), port("metrics", 9100, Some("10.24.0.9"))];
+
+        let specs = |specs: &[&str]| -> Result<Vec<String>> {
+            let specs: Vec<String> = specs.iter().map(|spec| spec.to_string()).collect();
+            let forwards = alloc_forwards(&ports, &specs, node)?;
+            Ok(forwards.iter().map(Forward::spec).collect())
+        };
+
+        assert_eq!(
+            specs(&[]).unwrap(),
+            ["8546:10.24.0.5:8546", "9100:10.24.0.9:9100"]
+        );
+        assert_eq!(specs(&["rpc:1234"]).unwrap(), ["1234:10.24.0.5:8546"]);
+        assert!(specs(&["http"]).is_err());
+        assert!(specs(&["rpc:x"]).is_err());
+        assert!(alloc_forwards(&[], &[], node).is_err());
+    }
+}
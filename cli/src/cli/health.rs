@@ -0,0 +1,179 @@
+use super::remote::{execute, HostResult, Outcome, OutputMode, Remote, RunOpts};
+use crate::types::BitteNode;
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::join_all;
+use log::*;
+use reqwest::Client;
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+
+/// How long to wait between attempts of a health check
+const INTERVAL: Duration = Duration::from_secs(5);
+
+/// What has to pass on a node before a rollout moves on to the next batch
+pub(crate) struct HealthGate {
+    /// run on the node, passing when it exits with 0
+    pub command: Option<Vec<String>>,
+    /// wait for the node's Nomad client to be ready and its Consul checks to
+    /// pass
+    pub wait_healthy: bool,
+    /// with a Nomad token, to look up the status of Nomad clients
+    pub nomad: Option<Arc<Client>>,
+    pub consul_token: Option<String>,
+    pub domain: String,
+    /// how long a node may take to pass
+    pub timeout: Duration,
+}
+
+/// The parts of `/v1/node/<id>` telling whether a Nomad client takes work
+#[derive(Deserialize)]
+struct NomadNode {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Status")]
+    status: String,
+    #[serde(rename = "Drain", default)]
+    drain: bool,
+}
+
+/// A check of `/v1/health/node/<name>`
+#[derive(Deserialize)]
+struct ConsulCheck {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Status")]
+    status: String,
+}
+
+impl HealthGate {
+    /// Waits for every node of `batch` whose command succeeded to pass, and
+    /// marks those that don't as unhealthy
+    pub async fn check_batch(&self, batch: &mut [HostResult], opts: &RunOpts) {
+        let waits = batch.iter().map(|result| async move {
+            if result.outcome.success() {
+                self.wait(&result.node, opts).await
+            } else {
+                Ok(())
+            }
+        });
+        let checked = join_all(waits).await;
+
+        for (result, checked) in batch.iter_mut().zip(checked) {
+            if let Err(err) = checked {
+                eprintln!("[{}] unhealthy: {:#}", result.label, err);
+                result.outcome = Outcome::Unhealthy;
+            }
+        }
+    }
+
+    /// Checks `node` every few seconds until it passes, or fails with the
+    /// last reason it didn't once the timeout is up; a check that hangs is cut
+    /// off at the deadline too
+    async fn wait(&self, node: &BitteNode, opts: &RunOpts) -> Result<()> {
+        let deadline = Instant::now() + self.timeout;
+
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            let checked = match tokio::time::timeout(remaining, self.check(node, opts)).await {
+                Ok(checked) => checked,
+                Err(_) => Err(anyhow!("health check didn't finish in time")),
+            };
+            match checked {
+                Ok(()) => return Ok(()),
+                Err(err) if Instant::now() >= deadline => {
+                    return Err(
+                        err.context(format!("still failing after {}s", self.timeout.as_secs()))
+                    )
+                }
+                Err(err) => {
+                    info!("{}: not healthy yet: {:#}", node.id, err);
+                    tokio::time::sleep(INTERVAL).await;
+                }
+            }
+        }
+    }
+
+    async fn check(&self, node: &BitteNode, opts: &RunOpts) -> Result<()> {
+        if let Some(command) = &self.command {
+            let printing = Mutex::new(());
+            let command = Remote::Ssh(command.clone());
+            let (outcome, _, stderr) =
+                execute(node, &command, opts, OutputMode::Json, "", &printing).await?;
+            if !outcome.success() {
+                bail!(
+                    "health check exited with {}: {}",
+                    outcome,
+                    String::from_utf8_lossy(&stderr).trim()
+                );
+            }
+        }
+
+        if self.wait_healthy {
+            let name = match (&self.nomad, &node.nomad_client) {
+                (Some(client), Some(nomad)) => {
+                    self.nomad_ready(client, &nomad.id.to_string()).await?
+                }
+                _ => node.name.clone(),
+            };
+            self.consul_passing(&name).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Fails unless the Nomad client with `id` is ready and not draining,
+    /// returning its name, which is also its name in Consul
+    async fn nomad_ready(&self, client: &Client, id: &str) -> Result<String> {
+        let url = format!("https://nomad.{}/v1/node/{}", self.domain, id);
+        let node: NomadNode = client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", &url))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))?;
+
+        if node.status != "ready" {
+            bail!("nomad client is {}", node.status);
+        }
+        if node.drain {
+            bail!("nomad client is draining");
+        }
+        Ok(node.name)
+    }
+
+    /// Fails unless all Consul checks of the node called `name` pass
+    async fn consul_passing(&self, name: &str) -> Result<()> {
+        let url = format!("https://consul.{}/v1/health/node/{}", self.domain, name);
+        let mut request = Client::new().get(&url);
+        if let Some(token) = &self.consul_token {
+            request = request.header("X-Consul-Token", token);
+        }
+
+        let checks: Vec<ConsulCheck> = request
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", &url))?
+            .error_for_status()?
+            .json()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))?;
+
+        if checks.is_empty() {
+            bail!("{} is not registered in consul", name);
+        }
+        let failing: Vec<String> = checks
+            .iter()
+            .filter(|check| check.status != "passing")
+            .map(|check| format!("{} ({})", check.name, check.status))
+            .collect();
+        if !failing.is_empty() {
+            bail!("consul checks not passing: {}", failing.join(", "));
+        }
+        Ok(())
+    }
+}
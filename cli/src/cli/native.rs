@@ -0,0 +1,302 @@
+use super::remote::{print_grouped, print_line, Outcome, OutputMode, CONNECT_FAILED};
+use crate::types::{known_hosts::KnownHosts, ssh::SshConfig, BitteNode};
+use anyhow::{anyhow, bail, Context, Result};
+use futures_util::future::{ready, Ready};
+use log::*;
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use thrussh::{client, ChannelMsg};
+use thrussh_keys::{key, PublicKeyBase64};
+use tokio::sync::{Mutex, OnceCell};
+
+/// How long to wait for a node to accept a connection, unless configured
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+type Connection = Arc<Mutex<client::Handle<Verifier>>>;
+
+/// Connections to nodes made by the built-in ssh client, opened on first use
+/// and shared by every command run on the same node
+///
+/// Only the user, port, identity and connect timeout of the `SshConfig`
+/// apply; `-o` options are specific to OpenSSH.
+pub(crate) struct Pool {
+    user: String,
+    port: u16,
+    connect_timeout: Duration,
+    key: Arc<key::KeyPair>,
+    config: Arc<client::Config>,
+    known: Arc<std::sync::Mutex<KnownHosts>>,
+    connections: std::sync::Mutex<HashMap<String, Arc<OnceCell<Connection>>>>,
+}
+
+/// Checks host keys against the cluster's known_hosts file, trusting and
+/// remembering the first key of nodes it has none for, like OpenSSH does with
+/// `StrictHostKeyChecking=accept-new`
+struct Verifier {
+    id: String,
+    known: Arc<std::sync::Mutex<KnownHosts>>,
+}
+
+impl client::Handler for Verifier {
+    type Error = anyhow::Error;
+    type FutureBool = Ready<Result<(Self, bool)>>;
+    type FutureUnit = Ready<Result<(Self, client::Session)>>;
+
+    fn finished_bool(self, b: bool) -> Self::FutureBool {
+        ready(Ok((self, b)))
+    }
+
+    fn finished(self, session: client::Session) -> Self::FutureUnit {
+        ready(Ok((self, session)))
+    }
+
+    fn check_server_key(self, server_public_key: &key::PublicKey) -> Self::FutureBool {
+        let presented = server_public_key.public_key_base64();
+
+        let trusted = match self.known.lock() {
+            Ok(mut known) if known.keys(&self.id).is_empty() => {
+                info!("{}: trusting host key on first connect", self.id);
+                let key = format!("{} {}", server_public_key.name(), presented);
+                known.set(&self.id, vec![key]);
+                true
+            }
+            Ok(known) => known
+                .keys(&self.id)
+                .iter()
+                .any(|key| key.split_whitespace().nth(1) == Some(presented.as_str())),
+            Err(_) => false,
+        };
+
+        if !trusted {
+            warn!("{}: host key doesn't match the known ones", self.id);
+        }
+        self.finished_bool(trusted)
+    }
+}
+
+impl Pool {
+    pub fn new(ssh: &SshConfig) -> Result<Self> {
+        let identity = ssh
+            .identity
+            .as_ref()
+            .context("the native ssh backend needs an identity file, it can't use ssh-agent")?;
+        let key = thrussh_keys::load_secret_key(identity, None)
+            .with_context(|| format!("failed to load ssh identity {}", identity.display()))?;
+
+        let known_hosts = ssh
+            .known_hosts
+            .as_ref()
+            .context("no place to keep host keys, HOME is not set")?;
+
+        Ok(Self {
+            user: ssh.user.clone(),
+            port: ssh.port,
+            connect_timeout: ssh.connect_timeout.unwrap_or(CONNECT_TIMEOUT),
+            key: Arc::new(key),
+            config: Arc::new(client::Config::default()),
+            known: Arc::new(std::sync::Mutex::new(KnownHosts::load(known_hosts)?)),
+            connections: Default::default(),
+        })
+    }
+
+    /// Writes the host keys trusted on first connect to the known_hosts file
+    pub fn save(&self) -> Result<()> {
+        self.known
+            .lock()
+            .map_err(|_| anyhow!("known hosts lock poisoned"))?
+            .save()
+    }
+
+    /// The connection to `node`, opening it unless another command did so
+    /// already
+    async fn connection(&self, node: &BitteNode) -> Result<Connection> {
+        let cell = self
+            .connections
+            .lock()
+            .map_err(|_| anyhow!("connection pool lock poisoned"))?
+            .entry(node.id.clone())
+            .or_default()
+            .clone();
+
+        cell.get_or_try_init(|| self.connect(node))
+            .await
+            .map(Arc::clone)
+    }
+
+    async fn connect(&self, node: &BitteNode) -> Result<Connection> {
+        let verifier = Verifier {
+            id: node.id.clone(),
+            known: Arc::clone(&self.known),
+        };
+        let connect = client::connect(Arc::clone(&self.config), (node.pub_ip, self.port), verifier);
+
+        let mut handle = tokio::time::timeout(self.connect_timeout, connect)
+            .await
+            .map_err(|_| anyhow!("connecting to {} timed out", node.pub_ip))?
+            .with_context(|| format!("failed to connect to {}", node.pub_ip))?;
+
+        if !handle
+            .authenticate_publickey(self.user.as_str(), Arc::clone(&self.key))
+            .await?
+        {
+            bail!("{} refused our key", self.destination(node));
+        }
+
+        Ok(Arc::new(Mutex::new(handle)))
+    }
+
+    /// Drops the connection to `node`, so the next command reconnects
+    fn forget(&self, node: &BitteNode) {
+        if let Ok(mut connections) = self.connections.lock() {
+            connections.remove(&node.id);
+        }
+    }
+
+    fn destination(&self, node: &BitteNode) -> String {
+        format!("{}@{}", self.user, node.pub_ip)
+    }
+
+    /// Runs `command` on `node` with `input` on its stdin, handing its stdout
+    /// and stderr to `sink` as they arrive, with `true` for stderr
+    ///
+    /// Only failing to connect or to open a session is an error; once the
+    /// command was sent it may be running, so losing it is reported to `sink`
+    /// as `Outcome::Error`, which is never retried.
+    pub async fn exec(
+        &self,
+        node: &BitteNode,
+        command: &str,
+        input: Option<&[u8]>,
+        mut sink: impl FnMut(bool, &[u8]),
+    ) -> Result<Outcome> {
+        let connection = self.connection(node).await?;
+        let channel = connection.lock().await.channel_open_session().await;
+        let mut channel = match channel {
+            Ok(channel) => channel,
+            Err(err) => {
+                self.forget(node);
+                return Err(err).context("failed to open a session");
+            }
+        };
+
+        let started = async {
+            channel.exec(true, command).await?;
+            if let Some(input) = input {
+                channel.data(input).await?;
+                channel.eof().await?;
+            }
+
+            let mut outcome = Outcome::Killed;
+            while let Some(msg) = channel.wait().await {
+                match msg {
+                    ChannelMsg::Data { data } => sink(false, &data),
+                    ChannelMsg::ExtendedData { data, ext: 1 } => sink(true, &data),
+                    ChannelMsg::ExitStatus { exit_status } => {
+                        outcome = Outcome::Exited(exit_status as i32)
+                    }
+                    _ => {}
+                }
+            }
+            Ok::<_, anyhow::Error>(outcome)
+        }
+        .await;
+
+        match started {
+            Ok(outcome) => Ok(outcome),
+            Err(err) => {
+                self.forget(node);
+                sink(true, format!("lost the session: {:#}\n", err).as_bytes());
+                Ok(Outcome::Error)
+            }
+        }
+    }
+}
+
+/// Collects or prints the output of a command as it arrives, the way `mode`
+/// asks for
+struct Output<'a> {
+    mode: OutputMode,
+    prefix: &'a str,
+    stdout: Vec<u8>,
+    stderr: Vec<u8>,
+}
+
+impl<'a> Output<'a> {
+    fn new(mode: OutputMode, prefix: &'a str) -> Self {
+        Self {
+            mode,
+            prefix,
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    fn streaming(&self) -> bool {
+        matches!(self.mode, OutputMode::Inherit | OutputMode::Prefixed)
+    }
+
+    fn push(&mut self, stderr: bool, data: &[u8]) {
+        let streaming = self.streaming();
+        let buffer = if stderr {
+            &mut self.stderr
+        } else {
+            &mut self.stdout
+        };
+        buffer.extend_from_slice(data);
+
+        if streaming {
+            while let Some(end) = buffer.iter().position(|byte| *byte == b'\n') {
+                let line: Vec<u8> = buffer.drain(..=end).collect();
+                print_line(self.prefix, &line, stderr);
+            }
+        }
+    }
+
+    /// Prints what is left to print, returning the captured stdout and stderr
+    async fn finish(self, printing: &Mutex<()>) -> Result<(Vec<u8>, Vec<u8>)> {
+        if self.streaming() {
+            for (buffer, stderr) in [(&self.stdout, false), (&self.stderr, true)] {
+                if !buffer.is_empty() {
+                    print_line(self.prefix, buffer, stderr);
+                }
+            }
+            return Ok((Vec::new(), Vec::new()));
+        }
+
+        if self.mode == OutputMode::Grouped {
+            print_grouped(self.prefix, &self.stdout, &self.stderr, printing).await?;
+        }
+        Ok((self.stdout, self.stderr))
+    }
+}
+
+/// Runs `command` on `node` through `pool`; failing to reach the node counts
+/// as exit code 255, like it does with OpenSSH, so that it is retried
+pub(crate) async fn run(
+    pool: &Pool,
+    node: &BitteNode,
+    command: &str,
+    input: Option<&[u8]>,
+    mode: OutputMode,
+    prefix: &str,
+    printing: &Mutex<()>,
+) -> Result<(Outcome, Vec<u8>, Vec<u8>)> {
+    let mut output = Output::new(mode, prefix);
+
+    let outcome = match pool
+        .exec(node, command, input, |stderr, data| {
+            output.push(stderr, data)
+        })
+        .await
+    {
+        Ok(outcome) => outcome,
+        Err(err) => {
+            output.push(true, format!("{:#}\n", err).as_bytes());
+            Outcome::Exited(CONNECT_FAILED)
+        }
+    };
+
+    let (stdout, stderr) = output.finish(printing).await?;
+    Ok((outcome, stdout, stderr))
+}
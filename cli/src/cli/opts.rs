@@ -32,6 +32,29 @@ pub struct Bitte {
     )]
     /// Regions containing Nomad clients
     aws_asg_regions: Option<Vec<String>>,
+    #[clap(long, env = "BITTE_CONFIG", value_name = "FILE")]
+    /// Config file, defaults to '$XDG_CONFIG_HOME/bitte/config.toml'
+    config: Option<String>,
+    #[clap(long, env = "BITTE_SSH_USER", value_name = "USER")]
+    /// User to log into nodes as [default: root]
+    ssh_user: Option<String>,
+    #[clap(long, env = "BITTE_SSH_PORT", value_name = "PORT")]
+    /// Port of the ssh daemon on the nodes [default: 22]
+    ssh_port: Option<u16>,
+    #[clap(long, env = "BITTE_SSH_IDENTITY", value_name = "FILE")]
+    /// Private key for ssh [default: 'secrets/ssh-<cluster>' in the current or a parent directory]
+    ssh_identity: Option<String>,
+    #[clap(long, conflicts_with = "ssh-identity")]
+    /// Authenticate using the keys held by ssh-agent instead of a key file
+    ssh_agent: bool,
+    #[clap(
+        long,
+        value_name = "OPTION",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// Extra ssh option, e.g. 'ServerAliveInterval=30', overriding the defaults
+    ssh_option: Vec<String>,
     #[clap(short, long, parse(from_occurrences), global = true, env = "RUST_LOG")]
     /// set log level: 'unset' is 'warn', '-v' is 'info', '-vv' is 'debug', ...
     verbose: i32,
@@ -0,0 +1,820 @@
+use super::health::HealthGate;
+use super::native::{self, Pool};
+use super::template;
+use crate::types::{ssh::SshConfig, BitteNode};
+use anyhow::{bail, Context, Result};
+use futures_util::future::join_all;
+use log::*;
+use prettytable::{cell, format, row, Row, Table};
+use serde::Serialize;
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::future::Future;
+use std::io::Write;
+use std::net::IpAddr;
+use std::process::{Command, ExitStatus, Stdio};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncRead, AsyncWriteExt, BufReader};
+use tokio::process::{Child, Command as AsyncCommand};
+use tokio::sync::{watch, Mutex, Semaphore};
+use tokio::task::JoinHandle;
+
+const COLORS: [u8; 6] = [32, 33, 34, 35, 36, 31];
+
+/// What ssh exits with when it fails to connect
+pub(crate) const CONNECT_FAILED: i32 = 255;
+
+/// How the output of a command running on several nodes is shown
+#[derive(Clone, Copy, PartialEq)]
+pub(crate) enum OutputMode {
+    /// hand the terminal to ssh, one node at a time
+    Inherit,
+    /// stream each line as it arrives, prefixed with the node it came from
+    Prefixed,
+    /// print all output of a node at once, after its command finished
+    Grouped,
+    /// print one JSON record per node, after its command finished
+    Json,
+}
+
+/// What to do on each node
+pub(crate) enum Remote {
+    /// run ssh with these arguments, usually a command, after replacing the
+    /// placeholders of `template` in them
+    Ssh(Vec<String>),
+    /// copy local files to `target` on the node with scp
+    Upload {
+        sources: Vec<String>,
+        target: String,
+        recursive: bool,
+    },
+    /// send a script to the node over stdin, run it with `args` and remove it
+    Script { script: Vec<u8>, args: Vec<String> },
+}
+
+impl Remote {
+    pub fn command(&self, node: &BitteNode, ssh: &SshConfig) -> Command {
+        match self {
+            Self::Ssh(args) => ssh_command(node, &template::expand(args, node), ssh),
+            Self::Script { .. } => ssh_command(node, &[self.shell_command(node)], ssh),
+            Self::Upload {
+                sources,
+                target,
+                recursive,
+            } => {
+                let mut cmd = Command::new("scp");
+                cmd.args(ssh.scp_args(node));
+                if *recursive {
+                    cmd.arg("-r");
+                }
+                cmd.args(sources);
+                cmd.arg(ssh.remote_path(node, target));
+                cmd
+            }
+        }
+    }
+
+    /// The command line run by the remote shell of `node`, empty for uploads
+    fn shell_command(&self, node: &BitteNode) -> String {
+        match self {
+            Self::Ssh(args) => template::expand(args, node).join(" "),
+            Self::Upload { .. } => String::new(),
+            Self::Script { args, .. } => {
+                let args: Vec<String> = template::substitute(args, node)
+                    .iter()
+                    .map(|arg| shell_quote(arg))
+                    .collect();
+                format!(
+                    "f=$(mktemp /tmp/bitte-run.XXXXXX) && trap 'rm -f \"$f\"' EXIT && \
+                     cat > \"$f\" && chmod +x \"$f\" && \"$f\" {}",
+                    args.join(" ")
+                )
+            }
+        }
+    }
+
+    /// What to send to the stdin of the remote command
+    fn input(&self) -> Option<&[u8]> {
+        match self {
+            Self::Script { script, .. } => Some(script),
+            _ => None,
+        }
+    }
+}
+
+/// Quotes `arg` for a POSIX shell
+pub(super) fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// What runs the command on each node
+pub(crate) enum Backend {
+    /// an `ssh` or `scp` process per node
+    OpenSsh,
+    /// the built-in ssh client; uploads still use scp
+    Native(Arc<Pool>),
+}
+
+/// The command to run on each node and how to go about it
+pub(crate) struct RunOpts {
+    pub command: Remote,
+    pub ssh: SshConfig,
+    pub backend: Backend,
+    pub mode: OutputMode,
+    pub fail_fast: bool,
+    /// how long the command may take on each node, retries included
+    pub timeout: Option<Duration>,
+    /// how often to try again on nodes ssh failed to connect to
+    pub retries: u32,
+}
+
+/// How running a command on a node ended
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Outcome {
+    Exited(i32),
+    /// terminated by a signal, or aborted by '--fail-fast'
+    Killed,
+    /// aborted after taking longer than '--timeout'
+    TimedOut,
+    /// the command succeeded, but the node didn't pass its health checks
+    Unhealthy,
+    /// never started because of '--fail-fast'
+    Skipped,
+    /// bitte failed to run the command or lost track of it; the reason is
+    /// kept as its stderr
+    Error,
+}
+
+impl Outcome {
+    pub fn success(&self) -> bool {
+        matches!(self, Self::Exited(0))
+    }
+
+    /// Whether ssh gave up on the connection, which it reports as 255
+    pub fn connect_failed(&self) -> bool {
+        matches!(self, Self::Exited(CONNECT_FAILED))
+    }
+
+    pub fn code(&self) -> Option<i32> {
+        match self {
+            Self::Exited(code) => Some(*code),
+            _ => None,
+        }
+    }
+
+    fn status(&self) -> &'static str {
+        match self {
+            Self::Exited(_) => "exited",
+            Self::Killed => "killed",
+            Self::TimedOut => "timed out",
+            Self::Unhealthy => "unhealthy",
+            Self::Skipped => "skipped",
+            Self::Error => "error",
+        }
+    }
+}
+
+impl From<ExitStatus> for Outcome {
+    fn from(status: ExitStatus) -> Self {
+        match status.code() {
+            Some(code) => Self::Exited(code),
+            None => Self::Killed,
+        }
+    }
+}
+
+impl Display for Outcome {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "{}", code),
+            _ => write!(f, "{}", self.status()),
+        }
+    }
+}
+
+/// The result of running a command on a single node
+pub(crate) struct HostResult {
+    pub label: String,
+    pub node: BitteNode,
+    pub outcome: Outcome,
+    pub duration: Duration,
+    pub stdout: Vec<u8>,
+    pub stderr: Vec<u8>,
+}
+
+/// A `HostResult` as printed by '--json'
+#[derive(Serialize)]
+struct HostRecord<'a> {
+    id: &'a str,
+    name: &'a str,
+    priv_ip: IpAddr,
+    pub_ip: IpAddr,
+    status: &'static str,
+    exit_code: Option<i32>,
+    duration_ms: u128,
+    stdout: Cow<'a, str>,
+    stderr: Cow<'a, str>,
+}
+
+impl HostResult {
+    fn skipped(node: BitteNode, label: String) -> Self {
+        Self {
+            label,
+            node,
+            outcome: Outcome::Skipped,
+            duration: Duration::default(),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    fn failed(node: BitteNode, label: String, err: impl Display) -> Self {
+        Self {
+            label,
+            node,
+            outcome: Outcome::Error,
+            duration: Duration::default(),
+            stdout: Vec::new(),
+            stderr: format!("{:#}", err).into_bytes(),
+        }
+    }
+
+    fn record(&self) -> HostRecord<'_> {
+        HostRecord {
+            id: &self.node.id,
+            name: &self.node.name,
+            priv_ip: self.node.priv_ip,
+            pub_ip: self.node.pub_ip,
+            status: self.outcome.status(),
+            exit_code: self.outcome.code(),
+            duration_ms: self.duration.as_millis(),
+            stdout: String::from_utf8_lossy(&self.stdout),
+            stderr: String::from_utf8_lossy(&self.stderr),
+        }
+    }
+}
+
+fn ssh_command(node: &BitteNode, args: &[String], ssh: &SshConfig) -> Command {
+    let mut cmd = Command::new("ssh");
+    cmd.args(ssh.args(node));
+    cmd.args(args);
+    cmd
+}
+
+pub(crate) async fn init_ssh(
+    node: &BitteNode,
+    args: Vec<String>,
+    ssh: &SshConfig,
+) -> Result<ExitStatus> {
+    let mut cmd = ssh_command(node, &args, ssh);
+    info!("cmd: {:?}", cmd);
+
+    cmd.spawn()
+        .with_context(|| "ssh command failed")?
+        .wait()
+        .with_context(|| "ssh command didn't finish?")
+}
+
+/// Opens local port forwards to `node` with `-L` specs, until interrupted
+pub(crate) async fn init_forward(
+    node: &BitteNode,
+    forwards: &[String],
+    ssh: &SshConfig,
+) -> Result<ExitStatus> {
+    let mut cmd = Command::new("ssh");
+    cmd.args(["-N", "-o", "ExitOnForwardFailure=yes"]);
+    for forward in forwards {
+        cmd.arg("-L").arg(forward);
+    }
+    cmd.args(ssh.args(node));
+    info!("cmd: {:?}", cmd);
+
+    cmd.spawn()
+        .with_context(|| "ssh command failed")?
+        .wait()
+        .with_context(|| "ssh command didn't finish?")
+}
+
+/// How `run_sequential` works its way through the nodes
+pub(crate) struct Rollout {
+    /// how many nodes to run the command on at a time
+    pub batch_size: usize,
+    /// how long to sleep between batches
+    pub delay: Option<Duration>,
+    /// what has to pass on the nodes of a batch before moving on
+    pub gate: Option<HealthGate>,
+}
+
+/// Runs the command on one batch of nodes after another; with a health gate,
+/// a node failing the command or its health checks stops the rollout
+pub(crate) async fn run_sequential(
+    nodes: Vec<BitteNode>,
+    opts: RunOpts,
+    rollout: Rollout,
+) -> Result<Vec<HostResult>> {
+    let labels = labels(&nodes);
+    let width = labels.iter().map(String::len).max().unwrap_or_default();
+    let colored = atty::is(atty::Stream::Stdout);
+    let printing = Mutex::new(());
+    let (abort, aborted) = watch::channel(false);
+    let stop_on_failure = opts.fail_fast || rollout.gate.is_some();
+
+    let mut results = Vec::with_capacity(nodes.len());
+    let mut iter = nodes.into_iter().zip(labels).enumerate().peekable();
+
+    while iter.peek().is_some() {
+        let runs = iter
+            .by_ref()
+            .take(rollout.batch_size)
+            .map(|(i, (node, label))| {
+                let prefix = prefix(&label, width, i, colored);
+                run_host(node, label, prefix, &opts, &printing, aborted.clone())
+            });
+        let mut batch = join_all(runs).await;
+
+        if let Some(gate) = &rollout.gate {
+            gate.check_batch(&mut batch, &opts).await;
+        }
+        for result in &batch {
+            report(result, opts.mode, &printing).await?;
+        }
+
+        if stop_on_failure && batch.iter().any(|result| !result.outcome.success()) {
+            abort.send(true).ok();
+        }
+        let skipping = *aborted.borrow();
+        results.extend(batch);
+
+        if let Some(delay) = rollout.delay {
+            if iter.peek().is_some() && !skipping {
+                tokio::time::sleep(delay).await;
+            }
+        }
+    }
+
+    Ok(results)
+}
+
+/// Runs the command on all `nodes` at once, with at most `concurrency` ssh
+/// processes alive at a time; `0` means no limit
+pub(crate) async fn run_parallel(
+    nodes: Vec<BitteNode>,
+    opts: RunOpts,
+    concurrency: usize,
+) -> Result<Vec<HostResult>> {
+    let limit = match concurrency {
+        0 => nodes.len().max(1),
+        n => n,
+    };
+    let limit = Arc::new(Semaphore::new(limit));
+    let printing = Arc::new(Mutex::new(()));
+    let (abort, aborted) = watch::channel(false);
+    let abort = Arc::new(abort);
+    let opts = Arc::new(opts);
+
+    let labels = labels(&nodes);
+    let width = labels.iter().map(String::len).max().unwrap_or_default();
+    let colored = atty::is(atty::Stream::Stdout);
+
+    let mut handles: Vec<JoinHandle<Result<HostResult>>> = Vec::with_capacity(nodes.len());
+    let mut targets = Vec::with_capacity(nodes.len());
+
+    for (i, (node, label)) in nodes.into_iter().zip(labels).enumerate() {
+        targets.push((node.clone(), label.clone()));
+        let prefix = prefix(&label, width, i, colored);
+        let limit = Arc::clone(&limit);
+        let printing = Arc::clone(&printing);
+        let abort = Arc::clone(&abort);
+        let aborted = aborted.clone();
+        let opts = Arc::clone(&opts);
+
+        let handle = tokio::spawn(async move {
+            let _permit = limit.acquire().await?;
+            let result = run_host(node, label, prefix, &opts, &printing, aborted).await;
+            report(&result, opts.mode, &printing).await?;
+
+            if opts.fail_fast && !result.outcome.success() {
+                abort.send(true).ok();
+            }
+
+            Ok(result)
+        });
+        handles.push(handle);
+    }
+
+    // a task that failed still gets its row in the summary
+    let mut results = Vec::with_capacity(handles.len());
+    for ((node, label), handle) in targets.into_iter().zip(handles) {
+        let result = match handle.await {
+            Ok(Ok(result)) => result,
+            Ok(Err(err)) => HostResult::failed(node, label, err),
+            Err(err) => HostResult::failed(node, label, err),
+        };
+        results.push(result);
+    }
+
+    Ok(results)
+}
+
+async fn run_host(
+    node: BitteNode,
+    label: String,
+    prefix: String,
+    opts: &RunOpts,
+    printing: &Mutex<()>,
+    aborted: watch::Receiver<bool>,
+) -> HostResult {
+    if *aborted.borrow() {
+        return HostResult::skipped(node, label);
+    }
+
+    let start = Instant::now();
+
+    let run = retry_connect(
+        opts.retries,
+        &prefix,
+        |(outcome, _, _): &(Outcome, Vec<u8>, Vec<u8>)| outcome.connect_failed(),
+        || execute(&node, &opts.command, opts, opts.mode, &prefix, printing),
+    );
+    let run = async {
+        match opts.timeout {
+            Some(timeout) => tokio::time::timeout(timeout, run)
+                .await
+                .unwrap_or_else(|_| Ok((Outcome::TimedOut, Vec::new(), Vec::new()))),
+            None => run.await,
+        }
+    };
+
+    // dropping the command on abort or timeout kills it
+    let (outcome, stdout, stderr) = tokio::select! {
+        output = run => match output {
+            Ok(output) => output,
+            Err(err) => (Outcome::Error, Vec::new(), format!("{:#}", err).into_bytes()),
+        },
+        _ = wait_aborted(aborted) => (Outcome::Killed, Vec::new(), Vec::new()),
+    };
+
+    HostResult {
+        label,
+        node,
+        outcome,
+        duration: start.elapsed(),
+        stdout,
+        stderr,
+    }
+}
+
+/// Runs `command` on `node` with the backend of `opts`, showing its output
+/// as `mode` asks for
+pub(super) async fn execute(
+    node: &BitteNode,
+    command: &Remote,
+    opts: &RunOpts,
+    mode: OutputMode,
+    prefix: &str,
+    printing: &Mutex<()>,
+) -> Result<(Outcome, Vec<u8>, Vec<u8>)> {
+    match (&opts.backend, command) {
+        (Backend::Native(_), Remote::Upload { .. }) | (Backend::OpenSsh, _) => {
+            run_process(node, command, &opts.ssh, mode, prefix, printing).await
+        }
+        (Backend::Native(pool), _) => {
+            let line = command.shell_command(node);
+            native::run(pool, node, &line, command.input(), mode, prefix, printing).await
+        }
+    }
+}
+
+/// Runs `attempt` again up to `retries` times for as long as its result
+/// shows that ssh failed to connect, waiting longer before each retry;
+/// `prefix` labels the notices about retries
+pub(crate) async fn retry_connect<T, F, Fut>(
+    retries: u32,
+    prefix: &str,
+    connect_failed: impl Fn(&T) -> bool,
+    mut attempt: F,
+) -> Result<T>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T>>,
+{
+    let mut tries = 0;
+    loop {
+        let result = attempt().await?;
+        if tries == retries || !connect_failed(&result) {
+            return Ok(result);
+        }
+
+        tries += 1;
+        let delay = retry_delay(tries);
+        let notice = format!(
+            "failed to connect, retrying in {}s ({}/{})",
+            delay.as_secs(),
+            tries,
+            retries
+        );
+        if prefix.is_empty() {
+            eprintln!("{}", notice);
+        } else {
+            eprintln!("{} {}", prefix, notice);
+        }
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// How long to wait before the `attempt`th retry, doubling from a second up
+/// to half a minute
+fn retry_delay(attempt: u32) -> Duration {
+    Duration::from_secs((1 << attempt.saturating_sub(1).min(5)).min(30))
+}
+
+/// Runs `command` on `node` in an `ssh` or `scp` process
+async fn run_process(
+    node: &BitteNode,
+    command: &Remote,
+    ssh: &SshConfig,
+    mode: OutputMode,
+    prefix: &str,
+    printing: &Mutex<()>,
+) -> Result<(Outcome, Vec<u8>, Vec<u8>)> {
+    let mut cmd = AsyncCommand::from(command.command(node, ssh));
+    if mode != OutputMode::Inherit {
+        cmd.stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped());
+    }
+    if command.input().is_some() {
+        cmd.stdin(Stdio::piped());
+    }
+    cmd.kill_on_drop(true);
+    info!("cmd: {:?}", cmd);
+
+    let mut child = cmd.spawn().with_context(|| "ssh command failed")?;
+    if let (Some(input), Some(mut stdin)) = (command.input(), child.stdin.take()) {
+        stdin.write_all(input).await?;
+    }
+    let (status, stdout, stderr) = wait_child(child, mode, prefix, printing).await?;
+    Ok((Outcome::from(status), stdout, stderr))
+}
+
+async fn wait_child(
+    mut child: Child,
+    mode: OutputMode,
+    prefix: &str,
+    printing: &Mutex<()>,
+) -> Result<(ExitStatus, Vec<u8>, Vec<u8>)> {
+    match mode {
+        OutputMode::Inherit => {
+            let status = child
+                .wait()
+                .await
+                .with_context(|| "ssh command didn't finish?")?;
+            Ok((status, Vec::new(), Vec::new()))
+        }
+        OutputMode::Prefixed => {
+            let stdout = child.stdout.take().context("ssh stdout not captured")?;
+            let stderr = child.stderr.take().context("ssh stderr not captured")?;
+            tokio::try_join!(
+                stream_lines(stdout, prefix, false),
+                stream_lines(stderr, prefix, true)
+            )?;
+            let status = child
+                .wait()
+                .await
+                .with_context(|| "ssh command didn't finish?")?;
+            Ok((status, Vec::new(), Vec::new()))
+        }
+        OutputMode::Grouped | OutputMode::Json => {
+            let output = child
+                .wait_with_output()
+                .await
+                .with_context(|| "ssh command didn't finish?")?;
+
+            if mode == OutputMode::Grouped {
+                print_grouped(prefix, &output.stdout, &output.stderr, printing).await?;
+            }
+
+            Ok((output.status, output.stdout, output.stderr))
+        }
+    }
+}
+
+async fn wait_aborted(mut aborted: watch::Receiver<bool>) {
+    while !*aborted.borrow() {
+        if aborted.changed().await.is_err() {
+            std::future::pending::<()>().await;
+        }
+    }
+}
+
+/// Prints the JSON record of a finished node as a single line, if asked to
+async fn report(result: &HostResult, mode: OutputMode, printing: &Mutex<()>) -> Result<()> {
+    if mode == OutputMode::Json {
+        let record = serde_json::to_string(&result.record())?;
+        let _printing = printing.lock().await;
+        println!("{}", record);
+    }
+    Ok(())
+}
+
+/// Prints a table of how the command went on each node, unless the results
+/// were already printed as JSON, and fails if it didn't succeed everywhere
+pub(crate) fn summarize(results: &[HostResult], mode: OutputMode) -> Result<()> {
+    if mode != OutputMode::Json {
+        let mut table = Table::new();
+        table.set_format(*format::consts::FORMAT_BOX_CHARS);
+        table.add_row(row![ bc => "Node", "Exit Code", "Duration"]);
+        for result in results {
+            let outcome = if result.outcome.success() {
+                cell!(result.outcome)
+            } else {
+                cell!(Fr -> result.outcome)
+            };
+            table.add_row(Row::new(vec![
+                cell!(result.label),
+                outcome,
+                cell!(format!("{:.1}s", result.duration.as_secs_f64())),
+            ]));
+        }
+        table.printstd();
+    }
+
+    let mut failures = failures(results);
+    let timed_out = failures.remove(&Outcome::TimedOut).unwrap_or_default();
+    let failed = failures.values().map(Vec::len).sum();
+
+    for (outcome, labels) in &failures {
+        match outcome {
+            Outcome::Exited(code) => eprintln!("exit code {}: {}", code, labels.join(", ")),
+            Outcome::Error => {}
+            _ => eprintln!("{}: {}", outcome, labels.join(", ")),
+        }
+    }
+    for result in results {
+        if let Outcome::Error = result.outcome {
+            eprintln!("{}: {}", result.label, String::from_utf8_lossy(&result.stderr));
+        }
+    }
+    if !timed_out.is_empty() {
+        eprintln!("timed out: {}", timed_out.join(", "));
+    }
+    match (failed, timed_out.len()) {
+        (0, 0) => {}
+        (failed, 0) => bail!("{} of {} nodes failed", failed, results.len()),
+        (0, timed_out) => bail!("{} of {} nodes timed out", timed_out, results.len()),
+        (failed, timed_out) => bail!(
+            "{} of {} nodes failed and {} timed out",
+            failed,
+            results.len(),
+            timed_out
+        ),
+    }
+
+    Ok(())
+}
+
+/// The nodes the command didn't succeed on, grouped by how it ended, exit
+/// codes first
+fn failures(results: &[HostResult]) -> BTreeMap<Outcome, Vec<&str>> {
+    let mut failures: BTreeMap<Outcome, Vec<&str>> = BTreeMap::new();
+    for result in results.iter().filter(|result| !result.outcome.success()) {
+        failures
+            .entry(result.outcome)
+            .or_default()
+            .push(&result.label);
+    }
+    failures
+}
+
+async fn stream_lines<R>(reader: R, prefix: &str, stderr: bool) -> Result<()>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut reader = BufReader::new(reader);
+    let mut line = Vec::new();
+
+    while reader.read_until(b'\n', &mut line).await? > 0 {
+        print_line(prefix, &line, stderr);
+        line.clear();
+    }
+
+    Ok(())
+}
+
+/// Prints a line of output of a node behind its prefix
+pub(super) fn print_line(prefix: &str, line: &[u8], stderr: bool) {
+    let text = String::from_utf8_lossy(line);
+    let text = text.trim_end_matches(&['\r', '\n'][..]);
+    if stderr {
+        eprintln!("{} {}", prefix, text);
+    } else {
+        println!("{} {}", prefix, text);
+    }
+}
+
+/// Prints all output of a node at once, below its prefix
+pub(super) async fn print_grouped(
+    prefix: &str,
+    stdout: &[u8],
+    stderr: &[u8],
+    printing: &Mutex<()>,
+) -> Result<()> {
+    let _printing = printing.lock().await;
+    println!("{}", prefix);
+    std::io::stdout().write_all(stdout)?;
+    std::io::stderr().write_all(stderr)?;
+    Ok(())
+}
+
+/// Names nodes by their `Name` tag, adding the instance id where that is
+/// ambiguous, as is the case for clients of the same auto-scaling group
+fn labels(nodes: &[BitteNode]) -> Vec<String> {
+    nodes
+        .iter()
+        .map(|node| {
+            let shared = nodes.iter().filter(|other| other.name == node.name).count() > 1;
+            if node.name.is_empty() {
+                node.id.clone()
+            } else if shared {
+                format!("{}:{}", node.name, node.id)
+            } else {
+                node.name.clone()
+            }
+        })
+        .collect()
+}
+
+fn prefix(label: &str, width: usize, index: usize, colored: bool) -> String {
+    let prefix = format!("{:<width$}", format!("[{}]", label), width = width + 2);
+    if colored {
+        format!("\x1b[{}m{}\x1b[0m", COLORS[index % COLORS.len()], prefix)
+    } else {
+        prefix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(name: &str, outcome: Outcome) -> HostResult {
+        HostResult {
+            label: name.to_owned(),
+            node: BitteNode::test(&format!("i-{}", name), name, "10.0.0.10"),
+            outcome,
+            duration: Duration::from_millis(1500),
+            stdout: Vec::new(),
+            stderr: Vec::new(),
+        }
+    }
+
+    fn summary(outcomes: &[Outcome]) -> String {
+        let results: Vec<HostResult> = outcomes
+            .iter()
+            .enumerate()
+            .map(|(i, outcome)| result(&format!("core-{}", i + 1), *outcome))
+            .collect();
+        match summarize(&results, OutputMode::Json) {
+            Ok(()) => String::new(),
+            Err(err) => err.to_string(),
+        }
+    }
+
+    #[test]
+    fn groups_failures_by_exit_code() {
+        let results = vec![
+            result("core-1", Outcome::Exited(0)),
+            result("core-2", Outcome::Exited(255)),
+            result("core-3", Outcome::Exited(1)),
+            result("core-4", Outcome::TimedOut),
+            result("core-5", Outcome::Exited(1)),
+            result("core-6", Outcome::Killed),
+        ];
+
+        let failures: Vec<(Outcome, Vec<&str>)> = failures(&results).into_iter().collect();
+        assert_eq!(
+            failures,
+            [
+                (Outcome::Exited(1), vec!["core-3", "core-5"]),
+                (Outcome::Exited(255), vec!["core-2"]),
+                (Outcome::Killed, vec!["core-6"]),
+                (Outcome::TimedOut, vec!["core-4"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn counts_timed_out_nodes_apart_from_failures() {
+        use Outcome::*;
+
+        assert_eq!(summary(&[Exited(0), Exited(0)]), "");
+        assert_eq!(summary(&[Exited(0), Exited(1), Exited(2)]), "2 of 3 nodes failed");
+        assert_eq!(summary(&[TimedOut, Exited(0)]), "1 of 2 nodes timed out");
+        assert_eq!(
+            summary(&[Exited(1), TimedOut, Error, Skipped]),
+            "3 of 4 nodes failed and 1 timed out"
+        );
+    }
+}
@@ -0,0 +1,117 @@
+use crate::types::{ssh::SshConfig, BitteNode};
+use anyhow::{Context, Result};
+use log::*;
+use std::fmt::Write;
+use std::path::PathBuf;
+
+/// The lines enclosing the hosts of `cluster` in `~/.ssh/config`
+fn markers(cluster: &str) -> (String, String) {
+    (
+        format!("# BEGIN bitte {}\n", cluster),
+        format!("# END bitte {}\n", cluster),
+    )
+}
+
+/// The `Host` aliases of a node: its name unless several nodes share it, its
+/// instance id and its Nomad client id, if known
+fn aliases(node: &BitteNode, nodes: &[BitteNode], prefix: &str) -> Vec<String> {
+    let mut aliases = Vec::new();
+
+    let shared = nodes.iter().filter(|other| other.name == node.name).count() > 1;
+    if !node.name.is_empty() && !shared {
+        aliases.push(format!("{}{}", prefix, node.name));
+    }
+    aliases.push(node.id.clone());
+    if let Some(client) = &node.nomad_client {
+        aliases.push(client.id.to_hyphenated().to_string());
+    }
+
+    aliases
+}
+
+/// Renders a `Host` block for every node; nodes without a public ip are
+/// reached through `bastion`, and skipped if there is none
+pub(crate) fn render(
+    nodes: &[BitteNode],
+    ssh: &SshConfig,
+    prefix: &str,
+    bastion: Option<&BitteNode>,
+) -> String {
+    let bastion = bastion.map(|bastion| aliases(bastion, nodes, prefix).swap_remove(0));
+    let mut config = String::new();
+
+    for node in nodes {
+        let aliases = aliases(node, nodes, prefix);
+
+        let (address, jump) = match &bastion {
+            _ if !node.pub_ip.is_unspecified() => (node.pub_ip, None),
+            Some(bastion) if !node.priv_ip.is_unspecified() => (node.priv_ip, Some(bastion)),
+            _ => {
+                warn!(
+                    "skipping {}: no public ip and no bastion",
+                    aliases.join(" ")
+                );
+                continue;
+            }
+        };
+
+        writeln!(config, "Host {}", aliases.join(" ")).ok();
+        writeln!(config, "  HostName {}", address).ok();
+        writeln!(config, "  User {}", ssh.user).ok();
+        writeln!(config, "  Port {}", ssh.port).ok();
+        if let Some(identity) = &ssh.identity {
+            writeln!(config, "  IdentityFile \"{}\"", identity.display()).ok();
+        }
+        if let Some(jump) = jump {
+            writeln!(config, "  ProxyJump {}", jump).ok();
+        }
+        for option in ssh.all_options() {
+            writeln!(config, "  {}", option).ok();
+        }
+        for option in ssh.host_key_options(node) {
+            writeln!(config, "  {}", option).ok();
+        }
+        config.push('\n');
+    }
+
+    config
+}
+
+/// Replaces the block marked for `cluster` in `~/.ssh/config` with `hosts`,
+/// adding it at the end if it isn't there yet
+pub(crate) fn include(cluster: &str, hosts: &str) -> Result<PathBuf> {
+    let home = std::env::var_os("HOME").context("HOME is not set")?;
+    let dir = PathBuf::from(home).join(".ssh");
+    let path = dir.join("config");
+
+    let current = match std::fs::read_to_string(&path) {
+        Ok(current) => current,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+        Err(err) => return Err(err).with_context(|| format!("failed to read {}", path.display())),
+    };
+
+    let (begin, end) = markers(cluster);
+    let block = format!("{}{}{}", begin, hosts, end);
+
+    let start = current.find(&begin);
+    let stop = start.and_then(|start| Some(start + current[start..].find(&end)? + end.len()));
+
+    let updated = match (start, stop) {
+        (Some(start), Some(stop)) => {
+            format!("{}{}{}", &current[..start], block, &current[stop..])
+        }
+        _ if current.is_empty() => block,
+        _ if current.ends_with("\n\n") => format!("{}{}", current, block),
+        _ if current.ends_with('\n') => format!("{}\n{}", current, block),
+        _ => format!("{}\n\n{}", current, block),
+    };
+
+    if updated != current {
+        std::fs::create_dir_all(&dir)
+            .with_context(|| format!("failed to create {}", dir.display()))?;
+        std::fs::write(&path, updated)
+            .with_context(|| format!("failed to write {}", path.display()))?;
+    }
+
+    Ok(path)
+}
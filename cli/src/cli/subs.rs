@@ -1,4 +1,5 @@
-use anyhow::{Context, Result};
+use crate::types::{select::Selector, ssh::SshBackend};
+use anyhow::{anyhow, Context, Result};
 use clap::{AppSettings, ArgSettings, Parser};
 use deploy::data as deployData;
 use deploy::settings as deploySettings;
@@ -8,7 +9,16 @@ use uuid::Uuid;
 pub enum SubCommands {
     Info(Info),
     Ssh(Ssh),
+    Run(Run),
+    SshConfig(SshConfig),
+    RefreshHostKeys(RefreshHostKeys),
+    Cp(Cp),
+    Forward(Forward),
+    Exec(Exec),
+    Logs(Logs),
+    AllocFs(AllocFs),
     Deploy(Deploy),
+    Audit(Audit),
     #[clap(setting = AppSettings::Hidden)]
     Completions(Completions),
 }
@@ -19,6 +29,9 @@ pub struct Info {
     #[clap(short, long)]
     /// output as JSON
     json: bool,
+    #[clap(long, value_name = "EXPR")]
+    /// only show nodes matching a selector expression, see 'ssh --help'
+    select: Option<Selector>,
 }
 
 #[derive(Parser, Default)]
@@ -29,36 +42,68 @@ pub struct Deploy {
     pub clients: bool,
     #[clap(flatten)]
     pub flags: deployData::Flags,
+    #[clap(long, value_name = "EXPR")]
+    /// only deploy nodes matching a selector expression, see 'ssh --help'
+    pub select: Option<Selector>,
+    #[clap(long, value_name = "JOB[:GROUP]", requires = "nomad")]
+    /// deploy all nodes running allocations of a Nomad job
+    pub job_hosts: Option<String>,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// for '--job-hosts': The Nomad token used to query node information
+    pub nomad: Option<Uuid>,
+    #[clap(long, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// for '--job-hosts': specify nomad namespace to search for <JOB>
+    pub namespace: String,
+    #[clap(
+        long,
+        value_name = "NEEDLE",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// skip nodes matching this needle; may be given more than once
+    pub exclude: Vec<String>,
+    #[clap(long, value_name = "N")]
+    /// deploy only N of the selected nodes, spread across zones and ASGs
+    pub sample: Option<usize>,
+    #[clap(long, requires = "sample")]
+    /// for '--sample': seed to pick the same nodes again
+    pub seed: Option<u64>,
 
     #[clap(flatten)]
     pub generic_settings: deploySettings::GenericSettings,
     /// nodes to deploy; takes one or more needles to match against:
-    /// private & public ip, node name and aws client id
+    /// private & public ip, node name and aws client id;
+    /// needles may be globs ('core-*'), regexes ('~^client-.*-c5') or
+    /// CIDR ranges ('10.0.64.0/18')
     pub nodes: Vec<String>,
 }
 
 #[derive(Parser)]
 /// SSH to instances
 pub struct Ssh {
-    #[clap(
-        short,
-        long,
-        requires_all = &["nomad", "namespace"],
-        number_of_values = 3,
-        value_names = &["JOB", "GROUP", "INDEX"],
-    )]
-    /// specify client by: job, group, alloc_index;
-    /// this will also 'cd' to the alloc dir if <ARGS> is empty
+    #[clap(short, long, requires = "nomad", value_name = "JOB[:GROUP[:INDEX]]")]
+    /// specify client by: job, job:group or job:group:alloc_index, or by
+    /// alloc id (prefix); this will also 'cd' to the alloc dir if <ARGS> is
+    /// empty
     job: Option<String>,
-    #[clap(
-        long,
-        value_name = "TOKEN",
-        env = "NOMAD_TOKEN",
-        parse(try_from_str = token_context),
-        setting = ArgSettings::HideEnvValues
-    )]
-    /// for '-j': The Nomad token used to query node information
-    nomad: Option<Uuid>,
+    #[clap(long, requires = "job", value_name = "STATUS")]
+    /// for '-j': match allocations with this client status instead of
+    /// 'running', e.g. 'failed' or 'complete'; 'any' matches all
+    status: Option<String>,
+    #[clap(long, requires = "job")]
+    /// for '-j': run <ARGS>, or a shell, inside the task's container instead
+    /// of on the node, with 'docker exec' for docker tasks or 'nsenter' for
+    /// exec tasks
+    enter: bool,
+    #[clap(long, requires = "enter", value_name = "TASK")]
+    /// for '--enter': the task to enter, if the allocation has several
+    task: Option<String>,
     #[clap(
         long,
         short,
@@ -77,18 +122,411 @@ pub struct Ssh {
     )]
     /// run <ARGS> on nodes in parallel
     parallel: bool,
-    #[clap(long, short, env = "NOMAD_NAMESPACE")]
-    /// for '-j': specify nomad namespace to search for <JOB>
-    namespace: Option<String>,
+    #[clap(flatten)]
+    multi: Multi,
+    #[clap(multiple_values = true)]
+    /// arguments to ssh
+    ///
+    /// With '-a' or '-p', the placeholders {name}, {id}, {priv_ip}, {pub_ip},
+    /// {zone}, {asg}, {nixos} and {nomad_id} are replaced by the shell quoted
+    /// attributes of each node, e.g. 'consul members | grep {priv_ip}'.
+    args: Option<String>,
+}
+
+#[derive(Parser)]
+/// Run a local script on instances
+pub struct Run {
+    #[clap(long, short, group = "multi", required_unless_present = "parallel")]
+    /// run <SCRIPT> on one node after another
+    all: bool,
+    #[clap(long, short, group = "multi", conflicts_with = "all")]
+    /// run <SCRIPT> on nodes in parallel
+    parallel: bool,
+    #[clap(flatten)]
+    multi: Multi,
+    /// local script to run; it is sent to each node, run and removed again
+    script: String,
+    #[clap(multiple_values = true)]
+    /// arguments to <SCRIPT>, after '--' if they start with '-'; may contain
+    /// the same placeholders as the arguments of 'ssh -a'
+    args: Vec<String>,
+}
+
+// Options of commands run on several nodes with '-a' or '-p', shared by
+// 'ssh' and 'run'; not a doc comment, which clap would take as their about
+#[derive(Parser)]
+pub struct Multi {
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "parallel",
+        parse(try_from_str = count_context)
+    )]
+    /// for '-p': run the command on at most N nodes at a time
+    concurrency: Option<usize>,
+    #[clap(long, requires = "parallel")]
+    /// for '-p': print the output of each node at once when it finishes,
+    /// instead of streaming prefixed lines
+    group_output: bool,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// for '--job-hosts' or 'ssh -j': The Nomad token used to query node
+    /// information
+    nomad: Option<Uuid>,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// for '--job-hosts' or 'ssh -j': specify nomad namespace to search for
+    /// <JOB>
+    namespace: String,
     #[clap(long, short = 'l', requires = "multi")]
-    /// for '-a' or '-p': execute commands only on Nomad clients
+    /// for '-a' or '-p': run the command only on Nomad clients
     clients: bool,
+    #[clap(long, value_name = "JOB[:GROUP]", requires_all = &["nomad", "multi"])]
+    /// for '-a' or '-p': run the command only on nodes running allocations
+    /// of a Nomad job
+    job_hosts: Option<String>,
+    #[clap(
+        long,
+        value_name = "NEEDLE",
+        multiple_occurrences = true,
+        number_of_values = 1,
+        requires = "multi"
+    )]
+    /// for '-a' or '-p': skip nodes matching this needle; may be given more
+    /// than once
+    exclude: Vec<String>,
+    #[clap(long, value_name = "N", requires = "multi")]
+    /// for '-a' or '-p': run the command only on N of the selected nodes,
+    /// spread across zones and ASGs
+    sample: Option<usize>,
+    #[clap(long, requires = "sample")]
+    /// for '--sample': seed to pick the same nodes again
+    seed: Option<u64>,
+    #[clap(long, requires = "multi", conflicts_with = "group-output")]
+    /// for '-a' or '-p': print one JSON record per node with its exit code,
+    /// duration and captured output, one per line
+    json: bool,
+    #[clap(long, requires = "multi")]
+    /// for '-a' or '-p': stop running the command on further nodes after the
+    /// first failure
+    fail_fast: bool,
     #[clap(long, short, requires = "all")]
-    /// for '-a': seconds to delay between commands
+    /// for '-a': seconds to delay between nodes
     delay: Option<usize>,
-    #[clap(multiple_values = true)]
-    /// arguments to ssh
-    args: Option<String>,
+    #[clap(
+        long,
+        value_name = "N",
+        requires = "all",
+        parse(try_from_str = count_context)
+    )]
+    /// for '-a': run the command on N nodes at a time, one batch after another
+    batch_size: Option<usize>,
+    #[clap(long, value_name = "COMMAND", requires = "all")]
+    /// for '-a': after the command, run COMMAND on each node until it exits
+    /// with 0 before moving on; a node that doesn't pass in time stops the
+    /// rollout. COMMAND may contain the same placeholders as the command
+    health_check: Option<String>,
+    #[clap(long, requires = "all")]
+    /// for '-a': after the command, wait for each node to pass its Consul
+    /// checks and, with '--nomad', for its Nomad client to be ready before
+    /// moving on; a node that doesn't pass in time stops the rollout
+    wait_healthy: bool,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "CONSUL_HTTP_TOKEN",
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// for '--wait-healthy': The Consul token used to query node health
+    consul_token: Option<String>,
+    #[clap(long, value_name = "SECONDS", default_value = "300")]
+    /// for '--health-check' or '--wait-healthy': how long a node may take to
+    /// pass
+    health_timeout: u64,
+    #[clap(arg_enum, long, value_name = "BACKEND", requires = "multi")]
+    /// for '-a' or '-p': run the command with an ssh process per node, or
+    /// with the built-in client, which needs no process per node and reuses
+    /// its connections [default: openssh]
+    backend: Option<SshBackend>,
+    #[clap(long, value_name = "SECONDS", requires = "multi")]
+    /// for '-a' or '-p': kill the command on a node when it takes longer
+    /// than this, retries included; such nodes are reported as timed out
+    timeout: Option<u64>,
+    #[clap(long, value_name = "SECONDS")]
+    /// give up connecting to a node after this long
+    connect_timeout: Option<u64>,
+    #[clap(long, value_name = "N", default_value = "0")]
+    /// try again up to N times on nodes ssh fails to connect to, which it
+    /// reports with exit code 255
+    retries: u32,
+    #[clap(long, value_name = "EXPR")]
+    /// only consider nodes matching a selector expression
+    ///
+    /// Compares node attributes with '=', '!=', '~=' (regex) or '!~', e.g.
+    /// 'zone=eu-central-1a', 'asg~=spot', 'node_type=c5.2xlarge' or
+    /// 'role=core'; combine them with '&', '|', '!' and parentheses.
+    /// Fields: id, name, nixos, priv_ip, pub_ip, node_type, zone, asg, role,
+    /// nomad_id
+    select: Option<Selector>,
+}
+
+#[derive(Parser)]
+/// Show who ran which commands and deploys against the cluster
+///
+/// Every 'ssh', 'run' and 'deploy' is recorded in
+/// '$XDG_DATA_HOME/bitte/audit.jsonl'.
+pub struct Audit {
+    #[clap(long)]
+    /// show records of all clusters, not just the one given by '--cluster'
+    all_clusters: bool,
+    #[clap(
+        long,
+        value_name = "NEEDLE",
+        multiple_occurrences = true,
+        number_of_values = 1
+    )]
+    /// only show records targeting a node with this id, name or ip, or
+    /// whose id or name starts with it; may be given more than once
+    node: Vec<String>,
+    #[clap(long, value_name = "TIME")]
+    /// only show records since TIME, e.g. '2021-12-24T18:00:00Z',
+    /// '2021-12-24' or '12h' ago
+    since: Option<String>,
+    #[clap(long, value_name = "TIME")]
+    /// only show records until TIME, in the same format as '--since'
+    until: Option<String>,
+    #[clap(long)]
+    /// print the matching records as JSON, one per line
+    json: bool,
+}
+
+#[derive(Parser)]
+/// Generate an ssh_config with a Host entry for every node
+pub struct SshConfig {
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// The Nomad token used to add Nomad client ids as host aliases
+    nomad: Option<Uuid>,
+    #[clap(long, value_name = "EXPR")]
+    /// only add nodes matching a selector expression, see 'ssh --help'
+    select: Option<Selector>,
+    #[clap(long, value_name = "STRING")]
+    /// prepend STRING to host aliases made from node names, e.g. 'testnet-'
+    prefix: Option<String>,
+    #[clap(long, value_name = "NEEDLE")]
+    /// node to jump through for nodes without a public ip; defaults to the
+    /// node named 'bastion', or else the first core node
+    bastion: Option<String>,
+    #[clap(long)]
+    /// write the entries into a marked block of '~/.ssh/config', replacing
+    /// the one from a previous run, instead of printing them
+    include: bool,
+}
+
+#[derive(Parser)]
+/// Copy files to and from nodes and allocation directories
+pub struct Cp {
+    #[clap(long, short)]
+    /// copy directories recursively
+    recursive: bool,
+    #[clap(long, short)]
+    /// upload to every node matching the destination needle at once, instead
+    /// of choosing one
+    all: bool,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// for allocation paths: The Nomad token used to query node information
+    nomad: Option<Uuid>,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// for allocation paths: nomad namespace to search for <JOB>
+    namespace: String,
+    #[clap(long, value_name = "STATUS", default_value = "running")]
+    /// for allocation paths: match allocations with this client status; 'any'
+    /// matches all
+    status: String,
+    #[clap(required = true, min_values = 2, value_name = "PATH")]
+    /// local paths, 'NEEDLE:PATH' on a node, or 'JOB/[GROUP[/INDEX]]:PATH'
+    /// relative to an allocation directory; the last one is the destination
+    ///
+    /// Whatever is before the ':' names an allocation when it contains a '/',
+    /// and a node otherwise, so a bare job or alloc id (prefix) needs a
+    /// trailing '/': 'mantis/:alloc/logs', 'mantis/miner/0:local' or
+    /// '6a1e2f/:alloc/data'. CIDR needles like '10.0.0.0/16:PATH' are nodes.
+    paths: Vec<String>,
+}
+
+#[derive(Parser)]
+/// Forward local ports to a node or to the ports of an allocation
+pub struct Forward {
+    #[clap(short, long, requires = "nomad", value_name = "JOB[:GROUP[:INDEX]]")]
+    /// forward to the ports of an allocation, specified by: job, job:group or
+    /// job:group:alloc_index, or by alloc id (prefix)
+    job: Option<String>,
+    #[clap(long, requires = "job", value_name = "STATUS")]
+    /// for '-j': match allocations with this client status instead of
+    /// 'running'; 'any' matches all
+    status: Option<String>,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// for '-j': The Nomad token used to query node information
+    nomad: Option<Uuid>,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// for '-j': specify nomad namespace to search for <JOB>
+    namespace: String,
+    /// a node needle followed by PORT, LOCAL:PORT or LOCAL:HOST:PORT; with
+    /// '-j', port labels as LABEL or LABEL:LOCAL, all ports if none are given
+    forwards: Vec<String>,
+}
+
+#[derive(Parser)]
+/// Run a command inside a task of an allocation through the Nomad API
+pub struct Exec {
+    #[clap(long, value_name = "TASK")]
+    /// the task to run <COMMAND> in; may be omitted if there is only one
+    task: Option<String>,
+    #[clap(long)]
+    /// don't allocate a TTY, even when attached to a terminal
+    no_tty: bool,
+    #[clap(long, value_name = "STATUS", default_value = "running")]
+    /// match allocations with this client status; 'any' matches all
+    status: String,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// The Nomad token used to query node information and exec
+    nomad: Uuid,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// nomad namespace to search for <JOB>
+    namespace: String,
+    #[clap(required = true, min_values = 1, max_values = 3, value_name = "JOB")]
+    /// specify the allocation by: job [group [alloc_index]], or by alloc id
+    /// (prefix)
+    job: Vec<String>,
+    #[clap(last = true)]
+    /// the command to run, '/bin/sh' by default
+    command: Vec<String>,
+}
+
+#[derive(Parser)]
+/// Print the logs of a task in an allocation through the Nomad API
+pub struct Logs {
+    #[clap(long, value_name = "TASK")]
+    /// the task to print logs of; may be omitted if there is only one
+    task: Option<String>,
+    #[clap(long)]
+    /// print stderr instead of stdout
+    stderr: bool,
+    #[clap(long, short)]
+    /// keep printing new output as it is written
+    follow: bool,
+    #[clap(long, value_name = "STATUS", default_value = "running")]
+    /// match allocations with this client status, e.g. 'failed'; 'any'
+    /// matches all
+    status: String,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// The Nomad token used to query node information and logs
+    nomad: Uuid,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// nomad namespace to search for <JOB>
+    namespace: String,
+    #[clap(required = true, min_values = 1, max_values = 3, value_name = "JOB")]
+    /// specify the allocation by: job [group [alloc_index]], or by alloc id
+    /// (prefix)
+    job: Vec<String>,
+}
+
+#[derive(Parser)]
+/// Browse the files of an allocation through the Nomad API
+pub struct AllocFs {
+    #[clap(subcommand)]
+    command: AllocFsCommands,
+}
+
+#[derive(Parser)]
+pub enum AllocFsCommands {
+    /// List a directory
+    Ls(AllocPath),
+    /// Print a file
+    Cat(AllocPath),
+    /// Show the size, mode and modification time of a file
+    Stat(AllocPath),
+    /// Download a directory as a tarball
+    Tar(AllocTar),
+}
+
+#[derive(Parser)]
+pub struct AllocPath {
+    #[clap(long, value_name = "STATUS", default_value = "running")]
+    /// match allocations with this client status, e.g. 'failed'; 'any'
+    /// matches all
+    status: String,
+    #[clap(
+        long,
+        value_name = "TOKEN",
+        env = "NOMAD_TOKEN",
+        parse(try_from_str = token_context),
+        setting = ArgSettings::HideEnvValues
+    )]
+    /// The Nomad token used to query node information and files
+    nomad: Uuid,
+    #[clap(long, short, env = "NOMAD_NAMESPACE", default_value = "default")]
+    /// nomad namespace to search for <JOB>
+    namespace: String,
+    #[clap(required = true, min_values = 2, max_values = 4, value_name = "JOB")]
+    /// specify the allocation by: job [group [alloc_index]], or by alloc id
+    /// (prefix); followed by a path in the allocation directory, e.g.
+    /// 'alloc/logs' or '/'
+    args: Vec<String>,
+}
+
+#[derive(Parser)]
+pub struct AllocTar {
+    #[clap(long, short, value_name = "FILE")]
+    /// write the tarball to FILE instead of stdout
+    output: Option<String>,
+    #[clap(flatten)]
+    path: AllocPath,
+}
+
+#[derive(Parser)]
+/// Fetch host keys again from the console output of nodes, e.g. after their
+/// keys were rotated
+pub struct RefreshHostKeys {
+    #[clap(long, value_name = "EXPR")]
+    /// only refresh nodes matching a selector expression, see 'ssh --help'
+    select: Option<Selector>,
+    /// nodes to refresh, all of them if none are given; takes needles like
+    /// 'deploy'
+    nodes: Vec<String>,
 }
 
 #[derive(Parser)]
@@ -109,3 +547,44 @@ pub enum Shells {
 fn token_context(string: &str) -> Result<Uuid> {
     Uuid::parse_str(string).with_context(|| format!("'{}' is not a valid UUID", string))
 }
+
+fn count_context(string: &str) -> Result<usize> {
+    match string.parse() {
+        Ok(0) => Err(anyhow!("must be at least 1")),
+        Ok(count) => Ok(count),
+        Err(_) => Err(anyhow!("'{}' is not a valid count", string)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::IntoApp;
+
+    const TOKEN: &str = "6f1e2c3a-0b4d-4e5f-8a9b-0c1d2e3f4a5b";
+
+    #[test]
+    fn ssh_job_takes_one_value() {
+        let matches = Ssh::into_app()
+            .try_get_matches_from(["ssh", "--nomad", TOKEN, "-j", "mantis", "uptime"])
+            .unwrap();
+        assert_eq!(matches.value_of("job"), Some("mantis"));
+        assert_eq!(matches.values_of("args").unwrap().collect::<Vec<_>>(), ["uptime"]);
+
+        let matches = Ssh::into_app()
+            .try_get_matches_from(["ssh", "--nomad", TOKEN, "-j", "mantis:miner:2"])
+            .unwrap();
+        assert_eq!(matches.value_of("job"), Some("mantis:miner:2"));
+        assert!(!matches.is_present("args"));
+    }
+
+    #[test]
+    fn forward_job_takes_one_value() {
+        let matches = Forward::into_app()
+            .try_get_matches_from(["forward", "--nomad", TOKEN, "-j", "mantis:miner", "rpc"])
+            .unwrap();
+        assert_eq!(matches.value_of("job"), Some("mantis:miner"));
+        assert_eq!(matches.value_of("namespace"), Some("default"));
+        assert_eq!(matches.values_of("forwards").unwrap().collect::<Vec<_>>(), ["rpc"]);
+    }
+}
@@ -0,0 +1,186 @@
+use super::remote::shell_quote;
+use crate::types::BitteNode;
+use anyhow::{bail, Result};
+
+/// The `{placeholders}` replaced by an attribute of each node in commands run
+/// on several nodes
+const PLACEHOLDERS: [&str; 8] = [
+    "name", "id", "priv_ip", "pub_ip", "zone", "asg", "nixos", "nomad_id",
+];
+
+/// Fails on the first placeholder in `args` that isn't one of `PLACEHOLDERS`
+pub(crate) fn check(args: &[String]) -> Result<()> {
+    for arg in args {
+        render(arg, |name| PLACEHOLDERS.contains(&name).then(String::new))?;
+    }
+    Ok(())
+}
+
+/// Replaces the placeholders in `args`, which are joined into a shell command,
+/// with the shell quoted attributes of `node`; unknown ones are kept as they
+/// are, `check` is there to catch those
+pub(crate) fn expand(args: &[String], node: &BitteNode) -> Vec<String> {
+    replace(args, |name| value(name, node).map(|value| shell_quote(&value)))
+}
+
+/// Like `expand`, but for arguments that are quoted as a whole, like those of
+/// a script, so the attributes are put in as they are
+pub(crate) fn substitute(args: &[String], node: &BitteNode) -> Vec<String> {
+    replace(args, |name| value(name, node))
+}
+
+fn replace(args: &[String], lookup: impl Fn(&str) -> Option<String>) -> Vec<String> {
+    args.iter()
+        .map(|arg| render(arg, &lookup).unwrap_or_else(|_| arg.clone()))
+        .collect()
+}
+
+fn value(name: &str, node: &BitteNode) -> Option<String> {
+    let value = match name {
+        "name" => node.name.clone(),
+        "id" => node.id.clone(),
+        "priv_ip" => node.priv_ip.to_string(),
+        "pub_ip" => node.pub_ip.to_string(),
+        "zone" => node.zone.clone().unwrap_or_default(),
+        "asg" => node.asg.clone().unwrap_or_default(),
+        "nixos" => node.nixos.clone(),
+        "nomad_id" => node
+            .nomad_client
+            .as_ref()
+            .map(|client| client.id.to_string())
+            .unwrap_or_default(),
+        _ => return None,
+    };
+    Some(value)
+}
+
+/// Replaces every `{word}` of lowercase letters and underscores in `arg` by
+/// what `lookup` returns for it; any other braces, like those of `${VAR}`,
+/// `{}`, `{a,b}` or `{{.Go}}` templates, are kept
+fn render(arg: &str, mut lookup: impl FnMut(&str) -> Option<String>) -> Result<String> {
+    let mut rendered = String::with_capacity(arg.len());
+    let mut rest = arg;
+
+    while let Some(start) = rest.find('{') {
+        rendered.push_str(&rest[..start]);
+        let tail = &rest[start..];
+
+        let word = tail[1..]
+            .find('}')
+            .map(|end| &tail[1..end + 1])
+            .filter(|word| {
+                !word.is_empty() && word.chars().all(|c| c.is_ascii_lowercase() || c == '_')
+            });
+
+        match word {
+            Some(word) if !rendered.ends_with('$') => match lookup(word) {
+                Some(value) => {
+                    rendered.push_str(&value);
+                    rest = &tail[word.len() + 2..];
+                }
+                None => bail!(
+                    "unknown placeholder '{{{}}}' in '{}', known are: {{{}}}; \
+                     add spaces inside the braces to keep them as they are",
+                    word,
+                    arg,
+                    PLACEHOLDERS.join("}, {")
+                ),
+            },
+            _ => {
+                rendered.push('{');
+                rest = &tail[1..];
+            }
+        }
+    }
+
+    rendered.push_str(rest);
+    Ok(rendered)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Vec<String> {
+        args.iter().map(|arg| arg.to_string()).collect()
+    }
+
+    fn node() -> BitteNode {
+        BitteNode {
+            zone: Some("eu-central-1a".to_owned()),
+            asg: Some("client-eu-central-1-spot".to_owned()),
+            ..BitteNode::test("i-0abc", "client-1", "10.0.96.5")
+        }
+    }
+
+    #[test]
+    fn replaces_placeholders() {
+        let expanded = expand(
+            &args(&["consul members | grep {priv_ip}", "{name}@{zone}", "{asg}"]),
+            &node(),
+        );
+        assert_eq!(
+            expanded,
+            args(&[
+                "consul members | grep '10.0.96.5'",
+                "'client-1'@'eu-central-1a'",
+                "'client-eu-central-1-spot'",
+            ])
+        );
+    }
+
+    #[test]
+    fn leaves_missing_attributes_empty() {
+        let node = BitteNode::test("i-0abc", "core-1", "10.0.0.10");
+        assert_eq!(
+            expand(&args(&["[{zone}] [{nomad_id}]"]), &node),
+            args(&["[''] ['']"])
+        );
+    }
+
+    #[test]
+    fn quotes_values_for_the_shell() {
+        let node = BitteNode {
+            asg: Some("spot; reboot".to_owned()),
+            ..BitteNode::test("i-0abc", "client 1's", "10.0.96.5")
+        };
+        assert_eq!(
+            expand(&args(&["echo {name}", "-- {asg}"]), &node),
+            args(&["echo 'client 1'\\''s'", "-- 'spot; reboot'"])
+        );
+        assert_eq!(
+            substitute(&args(&["--asg={asg}"]), &node),
+            args(&["--asg=spot; reboot"])
+        );
+    }
+
+    #[test]
+    fn keeps_other_braces() {
+        let kept = args(&[
+            "echo ${HOME} $${id}",
+            "find . -exec rm {} +",
+            "cp a.{conf,bak}",
+            "nomad status -t '{{.ID}}'",
+            "{ name }",
+            "{Name}",
+            "awk '{print $1}'",
+            "{",
+            "}{",
+        ]);
+        check(&kept).unwrap();
+        assert_eq!(expand(&kept, &node()), kept);
+    }
+
+    #[test]
+    fn rejects_unknown_placeholders() {
+        let err = check(&args(&["echo {name}", "echo {hostname}"])).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("unknown placeholder '{hostname}' in 'echo {hostname}'"));
+
+        check(&args(&[
+            "{name} {id} {priv_ip} {pub_ip} {zone} {asg} {nixos} {nomad_id}",
+        ]))
+        .unwrap();
+    }
+}
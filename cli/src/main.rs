@@ -24,16 +24,76 @@ async fn main() -> Result<()> {
     };
 
     match matches.subcommand() {
-        Some(("deploy", sub)) => cli::deploy(sub, run(false, None)).await?,
+        Some(("deploy", sub)) => {
+            let token: Option<Uuid> = if sub.is_present("job-hosts") {
+                sub.value_of_t("nomad").ok()
+            } else {
+                None
+            };
+            cli::deploy(sub, run(false, token)).await?
+        }
         Some(("info", sub)) => cli::info(sub, run(true, None)).await?,
         Some(("ssh", sub)) => {
-            let token: Option<Uuid> = if sub.is_present("job") {
+            let token: Option<Uuid> = if sub.is_present("job")
+                || sub.is_present("job-hosts")
+                || sub.is_present("wait-healthy")
+            {
                 sub.value_of_t("nomad").ok()
             } else {
                 None
             };
             cli::ssh(sub, run(true, token)).await?
         }
+        Some(("run", sub)) => {
+            let token: Option<Uuid> =
+                if sub.is_present("job-hosts") || sub.is_present("wait-healthy") {
+                    sub.value_of_t("nomad").ok()
+                } else {
+                    None
+                };
+            cli::run(sub, run(true, token)).await?
+        }
+        Some(("ssh-config", sub)) => {
+            let token: Option<Uuid> = sub.value_of_t("nomad").ok();
+            cli::ssh_config(sub, run(true, token)).await?
+        }
+        Some(("refresh-host-keys", sub)) => cli::refresh_host_keys(sub, run(true, None)).await?,
+        Some(("cp", sub)) => {
+            let paths: Vec<cli::copy::Endpoint> = sub.values_of_t("paths").unwrap_or_default();
+            let token: Option<Uuid> = if paths.iter().any(|path| path.needs_nomad()) {
+                sub.value_of_t("nomad").ok()
+            } else {
+                None
+            };
+            cli::cp(sub, run(true, token)).await?
+        }
+        Some(("forward", sub)) => {
+            let token: Option<Uuid> = if sub.is_present("job") {
+                sub.value_of_t("nomad").ok()
+            } else {
+                None
+            };
+            cli::forward(sub, run(true, token)).await?
+        }
+        Some(("exec", sub)) => {
+            let token: Option<Uuid> = sub.value_of_t("nomad").ok();
+            cli::exec(sub, run(true, token)).await?
+        }
+        Some(("logs", sub)) => {
+            let token: Option<Uuid> = sub.value_of_t("nomad").ok();
+            cli::logs(sub, run(true, token)).await?
+        }
+        Some(("alloc-fs", sub)) => {
+            let token: Option<Uuid> = match sub.subcommand() {
+                Some((_, sub)) => sub.value_of_t("nomad").ok(),
+                None => None,
+            };
+            cli::alloc_fs(sub, run(true, token)).await?
+        }
+        Some(("audit", sub)) => {
+            cli::init_log(matches.occurrences_of("verbose"));
+            cli::audit(sub, matches.value_of("name").unwrap_or_default())?
+        }
         Some(("completions", sub)) => cli::completions(sub, app).await?,
         _ => (),
     }
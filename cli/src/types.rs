@@ -1,4 +1,8 @@
 pub mod error;
+pub mod known_hosts;
+pub mod needle;
+pub mod select;
+pub mod ssh;
 
 use aws_sdk_ec2::{
     model::{Filter, Instance, Tag},
@@ -8,14 +12,16 @@ use clap::{ArgEnum, ArgMatches};
 use serde::{de::Deserializer, Deserialize, Serialize};
 use std::cmp::Ordering;
 use std::collections::hash_set::HashSet;
+use std::collections::BTreeMap;
 use std::env;
 use std::fmt::{Display, Formatter};
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::{Duration, SystemTime};
 
-use anyhow::{Context, Result};
+use anyhow::{bail, Context, Result};
 use enum_utils::FromStr;
+use prettytable::{cell, format, row, Table};
 use std::net::{IpAddr, Ipv4Addr};
 use uuid::Uuid;
 
@@ -26,9 +32,15 @@ use reqwest::{
     Client,
 };
 
+use crate::utils::nomad::{Driver, Port};
 use error::Error;
+use needle::Needle;
+use select::Selector;
+use ssh::SshConfig;
 
+use rand::{rngs::StdRng, seq::SliceRandom, SeedableRng};
 use regex::Regex;
+use strsim::levenshtein;
 
 #[derive(Serialize, Deserialize)]
 pub struct VaultLogin {
@@ -68,6 +80,10 @@ pub struct BitteCluster {
     pub provider: BitteProvider,
     #[serde(skip)]
     pub nomad_api_client: Option<Arc<Client>>,
+    #[serde(skip)]
+    pub nomad_token: Option<Uuid>,
+    #[serde(skip)]
+    pub ssh: SshConfig,
     pub ttl: SystemTime,
 }
 
@@ -111,7 +127,7 @@ impl NomadClient {
     }
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct BitteNode {
     pub id: String,
     pub name: String,
@@ -137,15 +153,18 @@ where
     Self: IntoIterator,
 {
     fn find_needle(self, needle: &str) -> Result<Self::Item>;
-    fn find_needles(self, needles: Vec<&str>) -> Self;
+    fn find_needles(self, needles: Vec<&str>) -> Result<Self>
+    where
+        Self: Sized;
     fn find_clients(self) -> Self;
-    fn find_with_job(
-        self,
-        name: &str,
-        group: &str,
-        index: &str,
-        namespace: &str,
-    ) -> Result<(Self::Item, NomadAlloc)>;
+    fn find_selected(self, selector: &Selector) -> Self;
+    fn find_job_hosts(self, query: &AllocQuery) -> Self;
+    fn exclude_needles(self, needles: Vec<&str>) -> Result<Self>
+    where
+        Self: Sized;
+    fn sample(self, count: usize, seed: u64) -> Self;
+    fn find_with_job(self, query: &AllocQuery) -> Result<(Self::Item, NomadAlloc)>;
+    fn find_allocs(self, query: &AllocQuery) -> Vec<(Self::Item, NomadAlloc)>;
 }
 
 impl Ord for BitteNode {
@@ -169,109 +188,259 @@ impl PartialEq for BitteNode {
 impl Eq for BitteNode {}
 
 impl BitteFind for BitteNodes {
-    fn find_with_job(
-        self,
-        name: &str,
-        group: &str,
-        index: &str,
-        namespace: &str,
-    ) -> Result<(Self::Item, NomadAlloc)> {
-        let node = self
-            .into_iter()
-            .find(|node| {
-                let client = &node.nomad_client;
-                if client.is_none() {
-                    return false;
-                };
+    fn find_with_job(self, query: &AllocQuery) -> Result<(Self::Item, NomadAlloc)> {
+        let hint = job_hint(&self, query);
+        let mut candidates = self.find_allocs(query);
 
-                let allocs = &client.as_ref().unwrap().allocs;
-                if allocs.is_none() || allocs.as_ref().unwrap().is_empty() {
-                    return false;
-                };
+        if candidates.len() > 1 {
+            return Err(Error::AmbiguousAlloc {
+                query: query.to_string(),
+                candidates,
+            }
+            .into());
+        }
 
-                allocs.as_ref().unwrap().iter().any(|alloc| {
-                    alloc.namespace == namespace
-                        && alloc.job_id == name
-                        && alloc.task_group == group
-                        && alloc.index.get() == index.parse().ok()
-                        && alloc.status == "running"
-                })
-            })
-            .with_context(|| {
-                format!(
-                    "{}, {}, {} does not match any running nomad allocations in namespace {}",
-                    name, group, index, namespace
-                )
-            })?;
-        let alloc = node
-            .nomad_client
-            .as_ref()
-            .unwrap()
-            .allocs
-            .as_ref()
-            .unwrap()
-            .iter()
-            .find(|alloc| {
-                alloc.namespace == namespace
-                    && alloc.job_id == name
-                    && alloc.task_group == group
-                    && alloc.index.get() == index.parse().ok()
-                    && alloc.status == "running"
-            })
-            .unwrap()
-            .clone();
-        Ok((node, alloc))
+        candidates.pop().with_context(|| {
+            format!(
+                "{} does not match any {}nomad allocations in namespace {}{}",
+                query,
+                query
+                    .status
+                    .as_ref()
+                    .map(|status| format!("{} ", status))
+                    .unwrap_or_default(),
+                query.namespace,
+                hint
+            )
+        })
+    }
+
+    fn find_allocs(self, query: &AllocQuery) -> Vec<(Self::Item, NomadAlloc)> {
+        let mut found = Vec::new();
+        for node in self.into_iter() {
+            let allocs: NomadAllocs = node
+                .allocs()
+                .iter()
+                .filter(|alloc| query.matches(alloc))
+                .cloned()
+                .collect();
+            for alloc in allocs {
+                found.push((node.clone(), alloc));
+            }
+        }
+        found
     }
 
     fn find_needle(self, needle: &str) -> Result<Self::Item> {
-        self.into_iter()
-            .find(|node| {
-                let ip = needle.parse::<IpAddr>().ok();
-
-                node.id == needle
-                    || node.name == needle
-                    || node
-                        .nomad_client
-                        .as_ref()
-                        .unwrap_or(&Default::default())
-                        .id
-                        .to_hyphenated()
-                        .to_string()
-                        == needle
-                    || Some(node.priv_ip) == ip
-                    || Some(node.pub_ip) == ip
-            })
-            .with_context(|| format!("{} does not match any nodes", needle))
+        let parsed: Needle = needle.parse()?;
+
+        let (mut candidates, others): (BitteNodes, BitteNodes) =
+            self.into_iter().partition(|node| parsed.matches(node));
+
+        if candidates.len() > 1 {
+            return Err(Error::AmbiguousNeedle {
+                needle: needle.to_owned(),
+                candidates,
+            }
+            .into());
+        }
+
+        candidates.pop().with_context(|| {
+            let names = others.iter().flat_map(|node| {
+                let nomad_id = node
+                    .nomad_client
+                    .as_ref()
+                    .map(|client| client.id.to_hyphenated().to_string());
+                vec![node.name.clone(), node.id.clone()]
+                    .into_iter()
+                    .chain(nomad_id)
+            });
+
+            match did_you_mean(needle, names) {
+                Some(hint) => format!(
+                    "{} does not match any nodes; did you mean {}?",
+                    needle, hint
+                ),
+                None => format!("{} does not match any nodes", needle),
+            }
+        })
     }
 
     fn find_clients(self) -> Self {
         self.into_iter().filter(|node| node.asg.is_some()).collect()
     }
 
-    fn find_needles(self, needles: Vec<&str>) -> Self {
+    fn find_selected(self, selector: &Selector) -> Self {
         self.into_iter()
-            .filter(|node| {
-                let ips: Vec<Option<IpAddr>> = needles
-                    .iter()
-                    .map(|needle| needle.parse::<IpAddr>().ok())
-                    .collect();
-
-                needles.contains(&&*node.id)
-                    || needles.contains(&&*node.name)
-                    || needles.contains(
-                        &&*node
-                            .nomad_client
-                            .as_ref()
-                            .unwrap_or(&Default::default())
-                            .id
-                            .to_hyphenated()
-                            .to_string(),
-                    )
-                    || ips.contains(&Some(node.priv_ip))
-                    || ips.contains(&Some(node.pub_ip))
-            })
+            .filter(|node| selector.matches(node))
+            .collect()
+    }
+
+    fn find_job_hosts(self, query: &AllocQuery) -> Self {
+        self.into_iter()
+            .filter(|node| node.allocs().iter().any(|alloc| query.matches(alloc)))
             .collect()
     }
+
+    fn exclude_needles(self, needles: Vec<&str>) -> Result<Self> {
+        let needles = needles
+            .into_iter()
+            .map(str::parse)
+            .collect::<Result<Vec<Needle>>>()?;
+
+        Ok(self
+            .into_iter()
+            .filter(|node| !needles.iter().any(|needle| needle.matches(node)))
+            .collect())
+    }
+
+    /// Picks `count` nodes, spreading them over zones and auto-scaling groups.
+    /// The same `seed` picks the same nodes for an unchanged cluster.
+    fn sample(mut self, count: usize, seed: u64) -> Self {
+        let mut rng = StdRng::seed_from_u64(seed);
+
+        self.sort_by(|a, b| a.id.cmp(&b.id));
+
+        let mut spreads: BTreeMap<(Option<String>, Option<String>), BitteNodes> = BTreeMap::new();
+        for node in self.into_iter() {
+            spreads
+                .entry((node.zone.clone(), node.asg.clone()))
+                .or_default()
+                .push(node);
+        }
+
+        let mut spreads: Vec<BitteNodes> = spreads.into_values().collect();
+        spreads.shuffle(&mut rng);
+        for spread in spreads.iter_mut() {
+            spread.shuffle(&mut rng);
+        }
+
+        let mut sample = Vec::with_capacity(count);
+        while sample.len() < count && spreads.iter().any(|spread| !spread.is_empty()) {
+            for spread in spreads.iter_mut() {
+                if sample.len() == count {
+                    break;
+                }
+                if let Some(node) = spread.pop() {
+                    sample.push(node);
+                }
+            }
+        }
+        sample
+    }
+
+    fn find_needles(self, needles: Vec<&str>) -> Result<Self> {
+        let needles = needles
+            .into_iter()
+            .map(str::parse)
+            .collect::<Result<Vec<Needle>>>()?;
+
+        Ok(self
+            .into_iter()
+            .filter(|node| needles.iter().any(|needle| needle.matches(node)))
+            .collect())
+    }
+}
+
+/// Lists the `choices` closest to `needle` by edit distance, if any are close
+/// enough to be a plausible typo
+fn did_you_mean<I>(needle: &str, choices: I) -> Option<String>
+where
+    I: IntoIterator,
+    I::Item: AsRef<str>,
+{
+    let cutoff = needle.chars().count() / 2 + 1;
+
+    let mut close: Vec<(usize, String)> = choices
+        .into_iter()
+        .map(|choice| {
+            let choice = choice.as_ref();
+            (levenshtein(needle, choice), choice.to_owned())
+        })
+        .filter(|(distance, choice)| *distance > 0 && *distance <= cutoff && !choice.is_empty())
+        .collect();
+    close.sort();
+    close.dedup();
+
+    if close.is_empty() {
+        return None;
+    }
+
+    let close: Vec<String> = close
+        .into_iter()
+        .take(3)
+        .map(|(_, choice)| choice)
+        .collect();
+    Some(close.join(", "))
+}
+
+fn job_hint(nodes: &[BitteNode], query: &AllocQuery) -> String {
+    let allocs: Vec<&NomadAlloc> = nodes
+        .iter()
+        .flat_map(|node| node.allocs())
+        .filter(|alloc| alloc.namespace == query.namespace)
+        .collect();
+
+    let groups: Vec<&str> = allocs
+        .iter()
+        .filter(|alloc| alloc.job_id == query.job)
+        .map(|alloc| alloc.task_group.as_str())
+        .collect();
+
+    let hint = match &query.group {
+        _ if groups.is_empty() => {
+            did_you_mean(&query.job, allocs.iter().map(|alloc| alloc.job_id.as_str()))
+                .map(|hint| format!("; did you mean job {}?", hint))
+        }
+        Some(group) if !groups.contains(&group.as_str()) => {
+            did_you_mean(group, groups).map(|hint| format!("; did you mean group {}?", hint))
+        }
+        _ => None,
+    };
+
+    hint.unwrap_or_default()
+}
+
+/// Renders allocations and the nodes they are placed on as a numbered table
+pub fn allocs_table(allocs: &[(BitteNode, NomadAlloc)]) -> Table {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BOX_CHARS);
+    table.add_row(
+        row![ bc => "#", "Alloc ID", "Job", "Group", "Index", "Status", "Node", "Instance ID"],
+    );
+    for (i, (node, alloc)) in allocs.iter().enumerate() {
+        table.add_row(row![
+            i + 1,
+            alloc.id.to_hyphenated().to_string()[..8],
+            alloc.job_id,
+            alloc.task_group,
+            alloc.index.get().map(|i| i.to_string()).unwrap_or_default(),
+            alloc.status,
+            node.name,
+            node.id,
+        ]);
+    }
+    table
+}
+
+/// Renders nodes as a numbered table, e.g. to pick one out of several candidates
+pub fn nodes_table(nodes: &[BitteNode]) -> Table {
+    let mut table = Table::new();
+    table.set_format(*format::consts::FORMAT_BOX_CHARS);
+    table
+        .add_row(row![ bc => "#", "Name", "Instance ID", "Private IP", "Public IP", "Zone", "ASG"]);
+    for (i, node) in nodes.iter().enumerate() {
+        table.add_row(row![
+            i + 1,
+            node.name,
+            node.id,
+            node.priv_ip,
+            node.pub_ip,
+            node.zone.as_deref().unwrap_or_default(),
+            node.asg.as_deref().unwrap_or_default(),
+        ]);
+    }
+    table
 }
 
 impl From<Instance> for BitteNode {
@@ -331,6 +500,14 @@ impl From<Instance> for BitteNode {
 }
 
 impl BitteNode {
+    /// The Nomad allocations placed on this node, if they were loaded
+    pub fn allocs(&self) -> &[NomadAlloc] {
+        self.nomad_client
+            .as_ref()
+            .and_then(|client| client.allocs.as_deref())
+            .unwrap_or_default()
+    }
+
     async fn find_nodes(
         provider: BitteProvider,
         name: String,
@@ -485,6 +662,212 @@ impl NomadAlloc {
             .with_context(|| format!("failed to decode response from: {}", &url))?;
         Ok(allocs)
     }
+
+    /// Fetches the full allocation, for what the list of allocations omits
+    pub async fn details(&self, client: &Client, domain: &str) -> Result<AllocDetails> {
+        let url = format!("https://nomad.{}/v1/allocation/{}", domain, self.id);
+        let details = client
+            .get(&url)
+            .send()
+            .await
+            .with_context(|| format!("failed to query: {}", &url))?
+            .error_for_status()?
+            .json::<AllocDetails>()
+            .await
+            .with_context(|| format!("failed to decode response from: {}", &url))?;
+        Ok(details)
+    }
+}
+
+/// The parts of a single Nomad allocation not included in `NomadAlloc`
+#[derive(Deserialize)]
+pub struct AllocDetails {
+    #[serde(rename = "AllocatedResources")]
+    allocated_resources: Option<AllocResources>,
+    #[serde(rename = "TaskGroup", default)]
+    task_group: String,
+    #[serde(rename = "Job")]
+    job: Option<AllocJob>,
+}
+
+#[derive(Deserialize)]
+struct AllocJob {
+    #[serde(rename = "TaskGroups", default)]
+    task_groups: Vec<AllocTaskGroup>,
+}
+
+#[derive(Deserialize)]
+struct AllocTaskGroup {
+    #[serde(rename = "Name")]
+    name: String,
+    #[serde(rename = "Tasks", default)]
+    tasks: Vec<AllocTask>,
+}
+
+#[derive(Deserialize)]
+struct AllocTask {
+    #[serde(rename = "Name")]
+    name: String,
+    /// kept as a string, since `Driver` only knows some of the drivers
+    #[serde(rename = "Driver", default)]
+    driver: String,
+}
+
+#[derive(Deserialize)]
+struct AllocResources {
+    #[serde(rename = "Tasks", default)]
+    tasks: BTreeMap<String, serde_json::Value>,
+    #[serde(rename = "Shared")]
+    shared: SharedPorts,
+}
+
+#[derive(Deserialize)]
+struct SharedPorts {
+    #[serde(rename = "Ports")]
+    ports: Option<Vec<Port>>,
+}
+
+impl AllocDetails {
+    /// The host ports allocated to the task group
+    pub fn ports(&self) -> &[Port] {
+        self.allocated_resources
+            .as_ref()
+            .and_then(|resources| resources.shared.ports.as_deref())
+            .unwrap_or_default()
+    }
+
+    /// The names of the tasks in the task group
+    pub fn tasks(&self) -> Vec<&str> {
+        self.allocated_resources
+            .iter()
+            .flat_map(|resources| resources.tasks.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// The name of the driver running `task`, e.g. `docker`
+    pub fn driver_name(&self, task: &str) -> Option<&str> {
+        self.job
+            .iter()
+            .flat_map(|job| &job.task_groups)
+            .filter(|group| group.name == self.task_group)
+            .flat_map(|group| &group.tasks)
+            .find(|candidate| candidate.name == task)
+            .map(|task| task.driver.as_str())
+    }
+
+    /// The driver running `task`, unless it is one `Driver` doesn't know
+    pub fn driver(&self, task: &str) -> Option<Driver> {
+        let name = self.driver_name(task)?;
+        serde_json::from_value(serde_json::Value::String(name.to_owned())).ok()
+    }
+
+    /// The task named `task`, or the only task if there is just one
+    pub fn task(&self, task: Option<&str>) -> Result<String> {
+        let tasks = self.tasks();
+        match (task, &tasks[..]) {
+            (Some(task), _) if tasks.contains(&task) => Ok(task.to_owned()),
+            (Some(task), _) => bail!("no task '{}', only: {}", task, tasks.join(", ")),
+            (None, [task]) => Ok(task.to_string()),
+            (None, _) => bail!(
+                "the allocation has several tasks, choose one with '--task': {}",
+                tasks.join(", ")
+            ),
+        }
+    }
+}
+
+/// Identifies Nomad allocations by job and optionally task group and index, or
+/// by (a prefix of) the allocation id
+#[derive(Debug, Clone)]
+pub struct AllocQuery {
+    pub job: String,
+    pub group: Option<String>,
+    pub index: Option<u32>,
+    pub namespace: String,
+    /// the client status to match, e.g. `running`; `None` matches any status
+    pub status: Option<String>,
+}
+
+impl AllocQuery {
+    /// Builds a query for the running allocations of `JOB[:GROUP]`
+    pub fn job_hosts(spec: &str, namespace: String) -> Self {
+        let (job, group) = match spec.split_once(':') {
+            Some((job, group)) => (job, Some(group.to_owned())),
+            None => (spec, None),
+        };
+
+        Self {
+            job: job.to_owned(),
+            group,
+            index: None,
+            namespace,
+            status: Some("running".into()),
+        }
+    }
+
+    /// Builds a query from a `JOB[:GROUP[:INDEX]]` or `ALLOC_ID` value
+    pub fn parse(spec: &str, namespace: String, status: Option<String>) -> Result<Self> {
+        let values: Vec<String> = spec.splitn(3, ':').map(str::to_owned).collect();
+        Self::new(&values, namespace, status)
+    }
+
+    /// Builds a query from `JOB [GROUP [INDEX]]` or `ALLOC_ID` values
+    pub fn new(values: &[String], namespace: String, status: Option<String>) -> Result<Self> {
+        let job = values
+            .first()
+            .cloned()
+            .context("a job name or allocation id is required")?;
+        let index = match values.get(2) {
+            Some(index) => Some(
+                index
+                    .parse()
+                    .with_context(|| format!("'{}' is not a valid allocation index", index))?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            job,
+            group: values.get(1).cloned(),
+            index,
+            namespace,
+            status: status.filter(|status| status != "any"),
+        })
+    }
+
+    pub fn matches(&self, alloc: &NomadAlloc) -> bool {
+        let by_id = self.group.is_none()
+            && self.job.len() >= 4
+            && alloc
+                .id
+                .to_hyphenated()
+                .to_string()
+                .starts_with(&self.job.to_lowercase());
+
+        let by_job = alloc.namespace == self.namespace
+            && alloc.job_id == self.job
+            && self.group.iter().all(|group| &alloc.task_group == group)
+            && self
+                .index
+                .iter()
+                .all(|index| alloc.index.get() == Some(*index));
+
+        (by_id || by_job) && self.status.iter().all(|status| &alloc.status == status)
+    }
+}
+
+impl Display for AllocQuery {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        write!(f, "{}", self.job)?;
+        if let Some(group) = &self.group {
+            write!(f, ", {}", group)?;
+        }
+        if let Some(index) = self.index {
+            write!(f, ", {}", index)?;
+        }
+        Ok(())
+    }
 }
 
 fn pull_index<'de, D>(deserializer: D) -> Result<AllocIndex, D::Error>
@@ -521,6 +904,8 @@ impl BitteCluster {
             }?
         };
 
+        let ssh = SshConfig::new(args, &name)?;
+
         let nomad_api_client = match token {
             Some(token) => {
                 let mut token = HeaderValue::from_str(&token.to_string())?;
@@ -572,6 +957,8 @@ impl BitteCluster {
             domain,
             provider,
             nomad_api_client,
+            nomad_token: token,
+            ssh,
             nodes,
             ttl: SystemTime::now()
                 .checked_add(Duration::from_secs(300))
@@ -586,3 +973,192 @@ impl BitteCluster {
         tokio::spawn(async move { BitteCluster::new(&args, token).await })
     }
 }
+
+#[cfg(test)]
+impl BitteNode {
+    /// A core node with nothing but an id, a name and a private ip
+    pub(crate) fn test(id: &str, name: &str, priv_ip: &str) -> Self {
+        Self {
+            id: id.to_owned(),
+            name: name.to_owned(),
+            priv_ip: priv_ip.parse().unwrap(),
+            pub_ip: "3.120.0.1".parse().unwrap(),
+            nixos: name.to_owned(),
+            nomad_client: None,
+            node_type: None,
+            zone: None,
+            asg: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes() -> BitteNodes {
+        vec![
+            BitteNode::test("i-0f3a6c21d4b7e8901", "core-1", "10.0.0.10"),
+            BitteNode::test("i-0c81e47b2a9d35f60", "core-2", "10.0.32.10"),
+            BitteNode::test("i-07d2b9e6f14a8c305", "monitoring", "10.0.0.20"),
+        ]
+    }
+
+    fn alloc(id: &str, job: &str, group: &str, index: u32) -> NomadAlloc {
+        NomadAlloc {
+            id: id.parse().unwrap(),
+            job_id: job.to_owned(),
+            namespace: "default".to_owned(),
+            task_group: group.to_owned(),
+            status: "running".to_owned(),
+            index: AllocIndex::Int(index),
+            node_id: Uuid::nil(),
+        }
+    }
+
+    fn query(spec: &str) -> AllocQuery {
+        AllocQuery::parse(spec, "default".to_owned(), Some("running".to_owned())).unwrap()
+    }
+
+    fn find_error(needle: &str) -> String {
+        nodes().find_needle(needle).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn suggests_the_closest_name() {
+        assert_eq!(
+            did_you_mean("monitorin", ["core-1", "monitoring"]),
+            Some("monitoring".to_owned())
+        );
+        assert_eq!(
+            find_error("monitorin"),
+            "monitorin does not match any nodes; did you mean monitoring?"
+        );
+    }
+
+    #[test]
+    fn suggests_up_to_three_names_by_distance() {
+        assert_eq!(
+            did_you_mean("core-0", ["core-12", "core-2", "monitoring", "core-1"]),
+            Some("core-1, core-2, core-12".to_owned())
+        );
+        assert_eq!(
+            did_you_mean("core-0", ["core-12", "core-3", "core-1", "core-2"]),
+            Some("core-1, core-2, core-3".to_owned())
+        );
+        assert_eq!(
+            find_error("core-3"),
+            "core-3 does not match any nodes; did you mean core-1, core-2?"
+        );
+    }
+
+    #[test]
+    fn suggests_nothing_when_nothing_is_close() {
+        assert_eq!(did_you_mean("vault", ["core-1", "monitoring"]), None);
+        assert_eq!(did_you_mean("core-1", ["core-1", ""]), None);
+        assert_eq!(find_error("vault"), "vault does not match any nodes");
+    }
+
+    #[test]
+    fn parses_job_group_and_index() {
+        let query = query("mantis:miner:2");
+        assert_eq!(query.job, "mantis");
+        assert_eq!(query.group.as_deref(), Some("miner"));
+        assert_eq!(query.index, Some(2));
+
+        assert!(AllocQuery::parse("mantis:miner:two", "default".to_owned(), None).is_err());
+    }
+
+    #[test]
+    fn matches_allocs_by_job_group_and_index() {
+        let miner0 = alloc("5d0a6f3e-1c2b-4a8e-9f70-3b6c1d2e4f50", "mantis", "miner", 0);
+        let miner1 = alloc("a41c9b27-7e3d-4f6a-8b15-c0d9e8f7a6b1", "mantis", "miner", 1);
+        let backup = alloc("0e9f8d7c-6b5a-4c3d-9e2f-1a0b9c8d7e6f", "mantis", "backup", 0);
+        let explorer = alloc("7b6a5c4d-3e2f-4a1b-8c9d-0e1f2a3b4c5d", "explorer", "miner", 0);
+
+        let matching = |spec: &str| {
+            let query = query(spec);
+            [&miner0, &miner1, &backup, &explorer]
+                .iter()
+                .filter(|alloc| query.matches(alloc))
+                .map(|alloc| alloc.id)
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(matching("mantis"), [miner0.id, miner1.id, backup.id]);
+        assert_eq!(matching("mantis:miner"), [miner0.id, miner1.id]);
+        assert_eq!(matching("mantis:miner:1"), [miner1.id]);
+        assert!(matching("mantis:miner:3").is_empty());
+        assert!(matching("vault").is_empty());
+
+        let failed = AllocQuery::parse("mantis", "default".to_owned(), Some("failed".into()));
+        assert!(!failed.unwrap().matches(&miner0));
+        let other = AllocQuery::parse("mantis", "staging".to_owned(), None);
+        assert!(!other.unwrap().matches(&miner0));
+    }
+
+    #[test]
+    fn matches_allocs_by_id_prefix() {
+        let miner = alloc("5d0a6f3e-1c2b-4a8e-9f70-3b6c1d2e4f50", "mantis", "miner", 0);
+
+        assert!(query("5d0a6f3e").matches(&miner));
+        assert!(query("5D0A").matches(&miner));
+        assert!(!query("5d0").matches(&miner));
+        assert!(!query("5d0a6f3e:miner").matches(&miner));
+        assert!(!query("a41c9b27").matches(&miner));
+    }
+
+    fn clients() -> BitteNodes {
+        (0..12)
+            .map(|i| BitteNode {
+                zone: Some(format!("eu-central-1{}", ["a", "b", "c"][i % 3])),
+                asg: Some(format!("client-{}", ["t3", "c5"][i % 2])),
+                ..BitteNode::test(&format!("i-{:02}", i), &format!("client-{}", i), "10.0.96.5")
+            })
+            .collect()
+    }
+
+    fn ids(nodes: &[BitteNode]) -> Vec<&str> {
+        nodes.iter().map(|node| node.id.as_str()).collect()
+    }
+
+    #[test]
+    fn samples_the_same_nodes_for_the_same_seed() {
+        let first = clients().sample(4, 7);
+        assert_eq!(first.len(), 4);
+        assert_eq!(ids(&first), ids(&clients().sample(4, 7)));
+
+        let mut reversed = clients();
+        reversed.reverse();
+        assert_eq!(ids(&first), ids(&reversed.sample(4, 7)));
+
+        let spreads: HashSet<_> = first
+            .iter()
+            .map(|node| (node.zone.clone(), node.asg.clone()))
+            .collect();
+        assert_eq!(spreads.len(), 4);
+    }
+
+    #[test]
+    fn samples_all_nodes_when_asked_for_more() {
+        let sample = clients().sample(20, 7);
+        let mut sampled = ids(&sample);
+        sampled.sort_unstable();
+        assert_eq!(sampled, ids(&clients()));
+
+        assert!(clients().sample(0, 7).is_empty());
+        assert!(BitteNodes::new().sample(3, 7).is_empty());
+    }
+
+    #[test]
+    fn excludes_nodes_matching_any_needle() {
+        let kept = nodes().exclude_needles(vec!["core-*", "10.0.0.20"]).unwrap();
+        assert!(kept.is_empty());
+
+        let kept = nodes().exclude_needles(vec!["core-1", "~^mon"]).unwrap();
+        assert_eq!(ids(&kept), ["i-0c81e47b2a9d35f60"]);
+
+        assert_eq!(nodes().exclude_needles(vec![]).unwrap().len(), 3);
+        assert!(nodes().exclude_needles(vec!["~("]).is_err());
+    }
+}
@@ -1,3 +1,5 @@
+use super::{allocs_table, nodes_table, BitteNode, NomadAlloc};
+
 #[derive(thiserror::Error, Debug)]
 #[error(transparent)]
 pub enum Error {
@@ -13,6 +15,16 @@ pub enum Error {
     Netrc(netrc_rs::Error),
     #[error("current BITTE_PROVIDER is not valid: {provider}")]
     Provider { provider: String },
+    #[error("{needle} matches {} nodes:\n{}", .candidates.len(), nodes_table(.candidates))]
+    AmbiguousNeedle {
+        needle: String,
+        candidates: Vec<BitteNode>,
+    },
+    #[error("{query} matches {} allocations:\n{}", .candidates.len(), allocs_table(.candidates))]
+    AmbiguousAlloc {
+        query: String,
+        candidates: Vec<(BitteNode, NomadAlloc)>,
+    },
 }
 
 // NOTE netrc_rs doesn't impl StdError so can't simply `#[from]`
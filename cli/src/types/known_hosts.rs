@@ -0,0 +1,411 @@
+use super::BitteNode;
+use anyhow::{anyhow, Context, Result};
+use aws_sdk_ec2::{Client as Ec2Client, Region};
+use log::*;
+use std::collections::{BTreeMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::Semaphore;
+
+const BEGIN_KEYS: &str = "-----BEGIN SSH HOST KEY KEYS-----";
+const END_KEYS: &str = "-----END SSH HOST KEY KEYS-----";
+
+/// Marks a node whose keys couldn't be read, in a comment ssh ignores
+const UNVERIFIED: &str = "# unverified";
+
+/// How many console outputs to fetch at a time, to stay clear of the EC2
+/// API rate limits
+const CONSOLE_CONCURRENCY: usize = 8;
+
+/// How long to wait before reading the console output of a node again after
+/// it had no keys, which is mostly the case while it's still booting
+const UNVERIFIED_TTL: Duration = Duration::from_secs(600);
+
+/// A known_hosts file for a single cluster, with host keys stored under the
+/// instance id of their node rather than its ip, which EC2 recycles
+pub struct KnownHosts {
+    path: PathBuf,
+    hosts: BTreeMap<String, Vec<String>>,
+    /// when reading the keys of a node last failed, in seconds since the epoch
+    unverified: BTreeMap<String, u64>,
+}
+
+/// What happened to the host keys of a node in `update`
+pub enum KeyUpdate {
+    /// read this many keys from the console output
+    Fetched(usize),
+    /// no keys could be read, ssh will trust the first one it sees
+    Unverified,
+    /// keys were known already
+    Known,
+}
+
+impl KnownHosts {
+    pub fn load(path: &Path) -> Result<Self> {
+        let content = match std::fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => {
+                return Err(err).with_context(|| format!("failed to read {}", path.display()))
+            }
+        };
+
+        let mut hosts: BTreeMap<String, Vec<String>> = BTreeMap::new();
+        let mut unverified = BTreeMap::new();
+        for line in content.lines() {
+            let line = line.trim();
+            if let Some(marker) = line.strip_prefix(UNVERIFIED) {
+                if let Some((id, since)) = marker.trim().split_once(' ') {
+                    if let Ok(since) = since.parse() {
+                        unverified.insert(id.to_owned(), since);
+                    }
+                }
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((host, key)) = line.split_once(char::is_whitespace) {
+                hosts
+                    .entry(host.to_owned())
+                    .or_default()
+                    .push(key.trim().to_owned());
+            }
+        }
+
+        Ok(Self {
+            path: path.to_owned(),
+            hosts,
+            unverified,
+        })
+    }
+
+    pub fn contains(&self, id: &str) -> bool {
+        self.hosts.contains_key(id)
+    }
+
+    /// The `<type> <key>` pairs known for the node with `id`
+    pub fn keys(&self, id: &str) -> &[String] {
+        self.hosts.get(id).map(Vec::as_slice).unwrap_or_default()
+    }
+
+    pub fn set(&mut self, id: &str, keys: Vec<String>) {
+        self.unverified.remove(id);
+        self.hosts.insert(id.to_owned(), keys);
+    }
+
+    pub fn remove(&mut self, id: &str) {
+        self.unverified.remove(id);
+        self.hosts.remove(id);
+    }
+
+    /// Remembers that the keys of the node with `id` couldn't be read
+    pub fn set_unverified(&mut self, id: &str) {
+        self.unverified.insert(id.to_owned(), now());
+    }
+
+    /// Whether reading the keys of the node with `id` failed too recently to
+    /// try again
+    pub fn recently_unverified(&self, id: &str) -> bool {
+        self.unverified
+            .get(id)
+            .map_or(false, |since| now().saturating_sub(*since) < UNVERIFIED_TTL.as_secs())
+    }
+
+    /// Drops the keys of instances that are no longer running in the cluster,
+    /// returning how many were dropped
+    pub fn prune(&mut self, nodes: &[BitteNode]) -> usize {
+        let live: HashSet<&str> = nodes.iter().map(|node| node.id.as_str()).collect();
+        let before = self.hosts.len();
+        self.hosts.retain(|id, _| live.contains(id.as_str()));
+        self.unverified.retain(|id, _| live.contains(id.as_str()));
+        before - self.hosts.len()
+    }
+
+    /// Writes the keys of `nodes` to `path` under the address ssh connects to
+    /// on `port`, for tools that can't give ssh a `HostKeyAlias` per node
+    pub fn save_by_address(&self, path: &Path, nodes: &[BitteNode], port: u16) -> Result<()> {
+        let mut content = String::new();
+        for node in nodes {
+            let host = match port {
+                22 => node.pub_ip.to_string(),
+                port => format!("[{}]:{}", node.pub_ip, port),
+            };
+            for key in self.keys(&node.id) {
+                content.push_str(&format!("{} {}\n", host, key));
+            }
+        }
+
+        std::fs::write(path, content).with_context(|| format!("failed to write {}", path.display()))
+    }
+
+    pub fn save(&self) -> Result<()> {
+        let mut content = String::new();
+        for (id, keys) in &self.hosts {
+            for key in keys {
+                content.push_str(&format!("{} {}\n", id, key));
+            }
+        }
+        for (id, since) in &self.unverified {
+            content.push_str(&format!("{} {} {}\n", UNVERIFIED, id, since));
+        }
+
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)
+                .with_context(|| format!("failed to create {}", dir.display()))?;
+        }
+        std::fs::write(&self.path, content)
+            .with_context(|| format!("failed to write {}", self.path.display()))
+    }
+}
+
+/// Prunes the known_hosts file at `path` and fetches the keys of `targets`
+/// from their console output, unless they are known already or had none a
+/// short while ago; with `refresh`, the known keys of `targets` are replaced
+pub async fn update(
+    path: &Path,
+    nodes: &[BitteNode],
+    targets: &[BitteNode],
+    refresh: bool,
+) -> Result<Vec<KeyUpdate>> {
+    let mut known = KnownHosts::load(path)?;
+    let pruned = known.prune(nodes);
+    if pruned > 0 {
+        info!("pruned host keys of {} nodes no longer running", pruned);
+    }
+
+    let limit = Arc::new(Semaphore::new(CONSOLE_CONCURRENCY));
+    let mut handles = Vec::with_capacity(targets.len());
+    for node in targets {
+        if refresh {
+            known.remove(&node.id);
+        }
+        let handle = if known.contains(&node.id) || known.recently_unverified(&node.id) {
+            None
+        } else {
+            let node = node.clone();
+            let limit = Arc::clone(&limit);
+            Some(tokio::spawn(async move {
+                let _permit = limit.acquire().await?;
+                console_host_keys(&node).await
+            }))
+        };
+        handles.push(handle);
+    }
+
+    let mut updates = Vec::with_capacity(targets.len());
+    for (node, handle) in targets.iter().zip(handles) {
+        let update = match handle {
+            None if known.contains(&node.id) => KeyUpdate::Known,
+            None => KeyUpdate::Unverified,
+            Some(handle) => match handle.await? {
+                Ok(keys) if !keys.is_empty() => {
+                    let count = keys.len();
+                    known.set(&node.id, keys);
+                    KeyUpdate::Fetched(count)
+                }
+                Ok(_) => {
+                    known.set_unverified(&node.id);
+                    KeyUpdate::Unverified
+                }
+                Err(err) => {
+                    warn!("{}: {:#}", node.id, err);
+                    known.set_unverified(&node.id);
+                    KeyUpdate::Unverified
+                }
+            },
+        };
+        updates.push(update);
+    }
+
+    known.save()?;
+    Ok(updates)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Reads the host keys a NixOS instance prints to its console at boot
+async fn console_host_keys(node: &BitteNode) -> Result<Vec<String>> {
+    let zone = node
+        .zone
+        .as_deref()
+        .ok_or_else(|| anyhow!("availability zone unknown"))?;
+    let region = zone.trim_end_matches(char::is_alphabetic).to_owned();
+
+    let config = aws_config::from_env()
+        .region(Region::new(region))
+        .load()
+        .await;
+    let output = Ec2Client::new(&config)
+        .get_console_output()
+        .instance_id(&node.id)
+        .send()
+        .await
+        .with_context(|| "failed to get console output")?;
+
+    let output = match output.output {
+        Some(output) => base64::decode(output.trim())?,
+        None => return Ok(Vec::new()),
+    };
+
+    Ok(parse_console_keys(&String::from_utf8_lossy(&output)))
+}
+
+/// Extracts the `<type> <key>` pairs between the host key markers, keeping
+/// only the last block, printed by the most recent boot
+fn parse_console_keys(output: &str) -> Vec<String> {
+    let mut keys = Vec::new();
+    let mut block: Option<Vec<String>> = None;
+
+    for line in output.lines() {
+        if line.contains(BEGIN_KEYS) {
+            block = Some(Vec::new());
+        } else if line.contains(END_KEYS) {
+            if let Some(block) = block.take() {
+                keys = block;
+            }
+        } else if let Some(block) = &mut block {
+            let mut words = line.split_whitespace();
+            while let Some(word) = words.next() {
+                if word.starts_with("ssh-") || word.starts_with("ecdsa-") {
+                    if let Some(key) = words.next() {
+                        block.push(format!("{} {}", word, key));
+                    }
+                    break;
+                }
+            }
+        }
+    }
+
+    keys
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reads_keys_between_markers() {
+        let output = format!(
+            "[    5.1] booting\n\
+             [   12.3] {}\n\
+             [   12.3] ssh-ed25519 AAAAC3Nza1 root@core-1\n\
+             [   12.3] ecdsa-sha2-nistp256 AAAAE2VjZH root@core-1\n\
+             [   12.3] ssh-rsa AAAAB3NzaC\n\
+             [   12.3] {}\n\
+             [   13.0] ssh-ed25519 AAAAafter root@core-1\n",
+            BEGIN_KEYS, END_KEYS
+        );
+
+        assert_eq!(
+            parse_console_keys(&output),
+            vec![
+                "ssh-ed25519 AAAAC3Nza1",
+                "ecdsa-sha2-nistp256 AAAAE2VjZH",
+                "ssh-rsa AAAAB3NzaC",
+            ]
+        );
+    }
+
+    #[test]
+    fn keeps_the_block_of_the_last_boot() {
+        let output = format!(
+            "{begin}\nssh-ed25519 AAAAfirst\n{end}\n\
+             reboot\n\
+             {begin}\nssh-ed25519 AAAAsecond\nssh-rsa AAAAsecond\n{end}\n",
+            begin = BEGIN_KEYS,
+            end = END_KEYS
+        );
+
+        assert_eq!(
+            parse_console_keys(&output),
+            vec!["ssh-ed25519 AAAAsecond", "ssh-rsa AAAAsecond"]
+        );
+    }
+
+    #[test]
+    fn ignores_an_unfinished_block() {
+        let output = format!(
+            "{begin}\nssh-ed25519 AAAAfirst\n{end}\n{begin}\nssh-ed25519 AAAAcut",
+            begin = BEGIN_KEYS,
+            end = END_KEYS
+        );
+
+        assert_eq!(parse_console_keys(&output), vec!["ssh-ed25519 AAAAfirst"]);
+    }
+
+    #[test]
+    fn finds_nothing_without_markers() {
+        assert!(parse_console_keys("ssh-ed25519 AAAAC3Nza1 root@core-1\n").is_empty());
+        assert!(parse_console_keys("").is_empty());
+    }
+
+    #[test]
+    fn writes_keys_by_address() {
+        let dir = std::env::temp_dir().join(format!("bitte-known-hosts-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let mut known = KnownHosts::load(&dir.join("cluster")).unwrap();
+        known.set("i-core", vec!["ssh-ed25519 AAAAcore".into()]);
+        known.set("i-other", vec!["ssh-ed25519 AAAAother".into()]);
+        let nodes = [
+            BitteNode::test("i-core", "core-1", "10.0.0.10"),
+            BitteNode::test("i-new", "core-2", "10.0.0.11"),
+        ];
+
+        let path = dir.join("by-address");
+        known.save_by_address(&path, &nodes, 22).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "3.120.0.1 ssh-ed25519 AAAAcore\n"
+        );
+
+        known.save_by_address(&path, &nodes, 2222).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&path).unwrap(),
+            "[3.120.0.1]:2222 ssh-ed25519 AAAAcore\n"
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn remembers_unverified_nodes_for_a_while() {
+        let dir = std::env::temp_dir().join(format!("bitte-unverified-{}", std::process::id()));
+        let path = dir.join("cluster");
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(
+            &path,
+            format!(
+                "i-stale ssh-ed25519 AAAAstale\n{} i-old 1000\n{} i-recent {}\n",
+                UNVERIFIED,
+                UNVERIFIED,
+                now() - 60
+            ),
+        )
+        .unwrap();
+
+        let mut known = KnownHosts::load(&path).unwrap();
+        assert_eq!(known.keys("i-stale"), ["ssh-ed25519 AAAAstale"]);
+        assert!(!known.recently_unverified("i-old"));
+        assert!(known.recently_unverified("i-recent"));
+        assert!(!known.recently_unverified("i-new"));
+
+        known.set_unverified("i-new");
+        known.set("i-recent", vec!["ssh-ed25519 AAAArecent".into()]);
+        known.save().unwrap();
+
+        let known = KnownHosts::load(&path).unwrap();
+        assert!(known.recently_unverified("i-new"));
+        assert!(!known.recently_unverified("i-recent"));
+        assert_eq!(known.keys("i-recent"), ["ssh-ed25519 AAAArecent"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,155 @@
+use super::BitteNode;
+use anyhow::{Context, Result};
+use glob::Pattern;
+use ipnet::IpNet;
+use regex::Regex;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// A pattern matched against the identifying attributes of a node
+#[derive(Debug, Clone)]
+pub enum Needle {
+    /// exact id, name, Nomad client id or ip address
+    Exact(String),
+    /// shell-style glob on id, name or Nomad client id, e.g. `core-*`
+    Glob(Pattern),
+    /// regex on id, name or Nomad client id, written as `~<regex>`
+    Regex(Regex),
+    /// private or public ip within a range, e.g. `10.0.64.0/18`
+    Cidr(IpNet),
+}
+
+impl Needle {
+    pub fn matches(&self, node: &BitteNode) -> bool {
+        let nomad_id = node
+            .nomad_client
+            .as_ref()
+            .map(|client| client.id.to_hyphenated().to_string());
+        let mut names = vec![node.id.as_str(), node.name.as_str()];
+        if let Some(nomad_id) = &nomad_id {
+            names.push(nomad_id);
+        }
+
+        match self {
+            Self::Exact(needle) => {
+                let ip = needle.parse::<IpAddr>().ok();
+
+                names.contains(&needle.as_str())
+                    || Some(node.priv_ip) == ip
+                    || Some(node.pub_ip) == ip
+            }
+            Self::Glob(pattern) => names.iter().any(|name| pattern.matches(name)),
+            Self::Regex(regex) => names.iter().any(|name| regex.is_match(name)),
+            Self::Cidr(net) => net.contains(&node.priv_ip) || net.contains(&node.pub_ip),
+        }
+    }
+}
+
+impl FromStr for Needle {
+    type Err = anyhow::Error;
+
+    fn from_str(needle: &str) -> Result<Self> {
+        if let Some(regex) = needle.strip_prefix('~') {
+            Regex::new(regex)
+                .map(Self::Regex)
+                .with_context(|| format!("'{}' is not a valid regex needle", needle))
+        } else if let Ok(net) = needle.parse::<IpNet>() {
+            Ok(Self::Cidr(net))
+        } else if needle.contains(&['*', '?', '['][..]) {
+            Pattern::new(needle)
+                .map(Self::Glob)
+                .with_context(|| format!("'{}' is not a valid glob needle", needle))
+        } else {
+            Ok(Self::Exact(needle.to_owned()))
+        }
+    }
+}
+
+impl Display for Needle {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        match self {
+            Self::Exact(needle) => write!(f, "{}", needle),
+            Self::Glob(pattern) => write!(f, "{}", pattern),
+            Self::Regex(regex) => write!(f, "~{}", regex),
+            Self::Cidr(net) => write!(f, "{}", net),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::NomadClient;
+    use uuid::Uuid;
+
+    fn needle(needle: &str) -> Needle {
+        needle.parse().unwrap()
+    }
+
+    #[test]
+    fn parses_each_kind() {
+        assert!(matches!(needle("core-1"), Needle::Exact(_)));
+        assert!(matches!(needle("10.0.0.1"), Needle::Exact(_)));
+        assert!(matches!(needle("core-*"), Needle::Glob(_)));
+        assert!(matches!(needle("client-[ab]"), Needle::Glob(_)));
+        assert!(matches!(needle("~^client-.*-c5"), Needle::Regex(_)));
+        assert!(matches!(needle("10.0.64.0/18"), Needle::Cidr(_)));
+    }
+
+    #[test]
+    fn rejects_invalid_patterns() {
+        let err = "~(".parse::<Needle>().unwrap_err();
+        assert_eq!(err.to_string(), "'~(' is not a valid regex needle");
+
+        let err = "core-[".parse::<Needle>().unwrap_err();
+        assert_eq!(err.to_string(), "'core-[' is not a valid glob needle");
+    }
+
+    #[test]
+    fn matches_ids_names_and_ips() {
+        let node = BitteNode::test("i-0abc", "core-1", "10.0.64.5");
+
+        assert!(needle("i-0abc").matches(&node));
+        assert!(needle("core-1").matches(&node));
+        assert!(needle("10.0.64.5").matches(&node));
+        assert!(needle("3.120.0.1").matches(&node));
+        assert!(!needle("core-2").matches(&node));
+        assert!(!needle("core").matches(&node));
+
+        assert!(needle("core-*").matches(&node));
+        assert!(needle("i-0a*").matches(&node));
+        assert!(!needle("client-*").matches(&node));
+
+        assert!(needle("~^core-\\d$").matches(&node));
+        assert!(!needle("~^client").matches(&node));
+
+        assert!(needle("10.0.64.0/18").matches(&node));
+        assert!(needle("3.120.0.0/16").matches(&node));
+        assert!(!needle("10.0.128.0/18").matches(&node));
+    }
+
+    #[test]
+    fn matches_nomad_client_ids() {
+        let id = Uuid::parse_str("6a1e2f3c-0000-4000-8000-000000000000").unwrap();
+        let node = BitteNode {
+            nomad_client: Some(NomadClient {
+                id,
+                allocs: None,
+                address: None,
+            }),
+            ..BitteNode::test("i-0abc", "client-1", "10.0.64.5")
+        };
+
+        assert!(needle("6a1e2f3c-0000-4000-8000-000000000000").matches(&node));
+        assert!(needle("6a1e2f3c-*").matches(&node));
+        assert!(needle("~^6a1e").matches(&node));
+    }
+
+    #[test]
+    fn displays_as_written() {
+        for written in ["core-1", "core-*", "~^core", "10.0.64.0/18"] {
+            assert_eq!(needle(written).to_string(), written);
+        }
+    }
+}
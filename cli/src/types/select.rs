@@ -0,0 +1,522 @@
+use super::BitteNode;
+use anyhow::{bail, Context, Result};
+use ipnet::IpNet;
+use regex::Regex;
+use std::str::FromStr;
+
+/// A boolean expression over node attributes, e.g.
+/// `role=client & (zone=eu-central-1a | asg~=spot) & !node_type=c5.2xlarge`
+///
+/// Comparisons take the form `<field><op><value>` where `<op>` is one of `=`,
+/// `!=`, `~=` (regex match) or `!~` (regex mismatch). They may be combined
+/// with `&`/`and`/`,`, `|`/`or`, `!`/`not` and parentheses. Values containing
+/// whitespace or operator characters can be quoted with `'` or `"`.
+#[derive(Debug, Clone)]
+pub enum Selector {
+    And(Box<Selector>, Box<Selector>),
+    Or(Box<Selector>, Box<Selector>),
+    Not(Box<Selector>),
+    Compare(Field, Op),
+}
+
+/// The node attributes a selector can compare against
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Field {
+    Id,
+    Name,
+    Nixos,
+    PrivIp,
+    PubIp,
+    NodeType,
+    Zone,
+    Asg,
+    Role,
+    NomadId,
+}
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Eq(String),
+    Ne(String),
+    Match(Regex),
+    NoMatch(Regex),
+}
+
+impl Field {
+    const NAMES: &'static [&'static str] = &[
+        "id",
+        "name",
+        "nixos",
+        "priv_ip",
+        "pub_ip",
+        "node_type",
+        "zone",
+        "asg",
+        "role",
+        "nomad_id",
+    ];
+
+    fn parse(name: &str) -> Option<Self> {
+        let field = match name {
+            "id" => Self::Id,
+            "name" => Self::Name,
+            "nixos" => Self::Nixos,
+            "priv_ip" => Self::PrivIp,
+            "pub_ip" => Self::PubIp,
+            "node_type" | "type" => Self::NodeType,
+            "zone" => Self::Zone,
+            "asg" => Self::Asg,
+            "role" => Self::Role,
+            "nomad_id" => Self::NomadId,
+            _ => return None,
+        };
+        Some(field)
+    }
+
+    fn value(&self, node: &BitteNode) -> String {
+        match self {
+            Self::Id => node.id.clone(),
+            Self::Name => node.name.clone(),
+            Self::Nixos => node.nixos.clone(),
+            Self::PrivIp => node.priv_ip.to_string(),
+            Self::PubIp => node.pub_ip.to_string(),
+            Self::NodeType => node.node_type.clone().unwrap_or_default(),
+            Self::Zone => node.zone.clone().unwrap_or_default(),
+            Self::Asg => node.asg.clone().unwrap_or_default(),
+            Self::Role => match node.asg {
+                Some(_) => "client".to_owned(),
+                None => "core".to_owned(),
+            },
+            Self::NomadId => node
+                .nomad_client
+                .as_ref()
+                .map(|client| client.id.to_hyphenated().to_string())
+                .unwrap_or_default(),
+        }
+    }
+
+    fn equals(&self, node: &BitteNode, expected: &str) -> bool {
+        let ip = match self {
+            Self::PrivIp => Some(node.priv_ip),
+            Self::PubIp => Some(node.pub_ip),
+            _ => None,
+        };
+
+        match (ip, expected.parse::<IpNet>()) {
+            (Some(ip), Ok(net)) => net.contains(&ip),
+            _ => self.value(node) == expected,
+        }
+    }
+}
+
+impl Selector {
+    pub fn matches(&self, node: &BitteNode) -> bool {
+        match self {
+            Self::And(lhs, rhs) => lhs.matches(node) && rhs.matches(node),
+            Self::Or(lhs, rhs) => lhs.matches(node) || rhs.matches(node),
+            Self::Not(selector) => !selector.matches(node),
+            Self::Compare(field, op) => match op {
+                Op::Eq(value) => field.equals(node, value),
+                Op::Ne(value) => !field.equals(node, value),
+                Op::Match(regex) => regex.is_match(&field.value(node)),
+                Op::NoMatch(regex) => !regex.is_match(&field.value(node)),
+            },
+        }
+    }
+}
+
+impl FromStr for Selector {
+    type Err = anyhow::Error;
+
+    fn from_str(input: &str) -> Result<Self> {
+        let tokens = tokenize(input)?;
+        let mut parser = Parser {
+            input,
+            tokens,
+            pos: 0,
+        };
+
+        let selector = parser.parse_or()?;
+        if let Some((token, offset)) = parser.tokens.get(parser.pos) {
+            return parser.fail(*offset, format!("unexpected '{}'", token));
+        }
+
+        Ok(selector)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Token {
+    Word(String),
+    Quoted(String),
+    Eq,
+    Ne,
+    Match,
+    NoMatch,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+impl std::fmt::Display for Token {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Word(word) => write!(f, "{}", word),
+            Self::Quoted(word) => write!(f, "{:?}", word),
+            Self::Eq => write!(f, "="),
+            Self::Ne => write!(f, "!="),
+            Self::Match => write!(f, "~="),
+            Self::NoMatch => write!(f, "!~"),
+            Self::And => write!(f, "&"),
+            Self::Or => write!(f, "|"),
+            Self::Not => write!(f, "!"),
+            Self::Open => write!(f, "("),
+            Self::Close => write!(f, ")"),
+        }
+    }
+}
+
+fn tokenize(input: &str) -> Result<Vec<(Token, usize)>> {
+    let mut tokens = Vec::new();
+    let mut chars = input.char_indices().peekable();
+
+    while let Some((offset, c)) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '&' | ',' => Token::And,
+            '|' => Token::Or,
+            '=' => Token::Eq,
+            '~' => match chars.next_if(|(_, c)| *c == '=') {
+                Some(_) => Token::Match,
+                None => bail!(caret(input, offset, "expected '~=' for a regex match")),
+            },
+            '!' => match chars.peek() {
+                Some((_, '=')) => {
+                    chars.next();
+                    Token::Ne
+                }
+                Some((_, '~')) => {
+                    chars.next();
+                    Token::NoMatch
+                }
+                _ => Token::Not,
+            },
+            '\'' | '"' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some((_, end)) if end == c => break,
+                        Some((_, next)) => word.push(next),
+                        None => bail!(caret(input, offset, "unterminated quote")),
+                    }
+                }
+                Token::Quoted(word)
+            }
+            _ => {
+                let mut word = c.to_string();
+                while let Some((_, next)) = chars.next_if(|(_, c)| !is_special(*c)) {
+                    word.push(next);
+                }
+                match word.as_str() {
+                    "and" => Token::And,
+                    "or" => Token::Or,
+                    "not" => Token::Not,
+                    _ => Token::Word(word),
+                }
+            }
+        };
+        tokens.push((token, offset));
+    }
+
+    Ok(tokens)
+}
+
+fn is_special(c: char) -> bool {
+    c.is_whitespace() || "()&|,=~!'\"".contains(c)
+}
+
+fn caret(input: &str, offset: usize, reason: &str) -> String {
+    format!(
+        "invalid selector: {}\n  {}\n  {}^",
+        reason,
+        input,
+        " ".repeat(input[..offset].chars().count())
+    )
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    tokens: Vec<(Token, usize)>,
+    pos: usize,
+}
+
+impl Parser<'_> {
+    fn fail<T>(&self, offset: usize, reason: String) -> Result<T> {
+        bail!(caret(self.input, offset, &reason))
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos).map(|(token, _)| token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens
+            .get(self.pos)
+            .map(|(_, offset)| *offset)
+            .unwrap_or_else(|| self.input.len())
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.pos).map(|(token, _)| token.clone());
+        self.pos += 1;
+        token
+    }
+
+    fn parse_or(&mut self) -> Result<Selector> {
+        let mut lhs = self.parse_and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            let rhs = self.parse_and()?;
+            lhs = Selector::Or(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_and(&mut self) -> Result<Selector> {
+        let mut lhs = self.parse_unary()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            let rhs = self.parse_unary()?;
+            lhs = Selector::And(Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn parse_unary(&mut self) -> Result<Selector> {
+        let offset = self.offset();
+        match self.next() {
+            Some(Token::Not) => Ok(Selector::Not(Box::new(self.parse_unary()?))),
+            Some(Token::Open) => {
+                let selector = self.parse_or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(selector),
+                    _ => self.fail(offset, "unclosed '('".into()),
+                }
+            }
+            Some(Token::Word(name)) => self.parse_compare(&name, offset),
+            Some(token) => self.fail(offset, format!("expected a field, found '{}'", token)),
+            None => self.fail(offset, "expected a field".into()),
+        }
+    }
+
+    fn parse_compare(&mut self, name: &str, offset: usize) -> Result<Selector> {
+        let field = match Field::parse(name) {
+            Some(field) => field,
+            None => {
+                return self.fail(
+                    offset,
+                    format!(
+                        "unknown field '{}', expected one of: {}",
+                        name,
+                        Field::NAMES.join(", ")
+                    ),
+                )
+            }
+        };
+
+        let op_offset = self.offset();
+        let op = match self.next() {
+            Some(op @ (Token::Eq | Token::Ne | Token::Match | Token::NoMatch)) => op,
+            _ => {
+                return self.fail(
+                    op_offset,
+                    format!("expected one of '=', '!=', '~=' or '!~' after '{}'", name),
+                )
+            }
+        };
+
+        let value_offset = self.offset();
+        let value = match self.next() {
+            Some(Token::Word(value)) | Some(Token::Quoted(value)) => value,
+            _ => return self.fail(value_offset, format!("expected a value for '{}'", name)),
+        };
+
+        let regex = |value: &str| {
+            Regex::new(value).with_context(|| caret(self.input, value_offset, "invalid regex"))
+        };
+
+        let op = match op {
+            Token::Eq => Op::Eq(value),
+            Token::Ne => Op::Ne(value),
+            Token::Match => Op::Match(regex(&value)?),
+            _ => Op::NoMatch(regex(&value)?),
+        };
+
+        Ok(Selector::Compare(field, op))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn select(input: &str) -> Selector {
+        input.parse().unwrap()
+    }
+
+    /// The message of the error `input` fails with, and the column its caret
+    /// points at
+    fn fail(input: &str) -> (String, usize) {
+        let err = input.parse::<Selector>().unwrap_err().to_string();
+        let mut lines = err.lines();
+        let reason = lines.next().unwrap().to_owned();
+        assert_eq!(lines.next(), Some(format!("  {}", input).as_str()));
+        let caret = lines.next().unwrap();
+        assert!(caret.ends_with('^'), "no caret in {:?}", err);
+        (reason, caret.len() - 3)
+    }
+
+    fn client(name: &str, zone: &str, asg: &str) -> BitteNode {
+        BitteNode {
+            zone: Some(zone.to_owned()),
+            asg: Some(asg.to_owned()),
+            node_type: Some("c5.2xlarge".to_owned()),
+            ..BitteNode::test(&format!("i-{}", name), name, "10.0.96.5")
+        }
+    }
+
+    #[test]
+    fn and_binds_tighter_than_or() {
+        let selector = select("role=core | zone=eu-central-1a & asg~=spot");
+        assert!(matches!(
+            &selector,
+            Selector::Or(lhs, rhs)
+                if matches!(**lhs, Selector::Compare(Field::Role, Op::Eq(_)))
+                    && matches!(**rhs, Selector::And(_, _))
+        ));
+
+        let core = BitteNode::test("i-core", "core-1", "10.0.0.10");
+        assert!(selector.matches(&core));
+        assert!(selector.matches(&client("client-1", "eu-central-1a", "spot-1")));
+        assert!(!selector.matches(&client("client-2", "eu-central-1b", "spot-1")));
+        assert!(!selector.matches(&client("client-3", "eu-central-1a", "demand-1")));
+
+        let grouped = select("(role=core | zone=eu-central-1a) & asg~=spot");
+        assert!(matches!(grouped, Selector::And(_, _)));
+        assert!(!grouped.matches(&core));
+    }
+
+    #[test]
+    fn not_binds_tighter_than_and() {
+        let selector = select("!role=core & zone=eu-central-1a");
+        assert!(matches!(
+            &selector,
+            Selector::And(lhs, _) if matches!(**lhs, Selector::Not(_))
+        ));
+        assert!(selector.matches(&client("client-1", "eu-central-1a", "spot-1")));
+        assert!(!selector.matches(&client("client-2", "eu-central-1b", "spot-1")));
+    }
+
+    #[test]
+    fn tells_not_equal_from_not() {
+        assert!(matches!(
+            select("zone!=eu-central-1a"),
+            Selector::Compare(Field::Zone, Op::Ne(value)) if value == "eu-central-1a"
+        ));
+        assert!(matches!(
+            select("!zone=eu-central-1a"),
+            Selector::Not(selector)
+                if matches!(*selector, Selector::Compare(Field::Zone, Op::Eq(_)))
+        ));
+        assert!(matches!(
+            select("asg!~spot"),
+            Selector::Compare(Field::Asg, Op::NoMatch(_))
+        ));
+        assert!(matches!(
+            select("! asg~=spot"),
+            Selector::Not(selector)
+                if matches!(*selector, Selector::Compare(Field::Asg, Op::Match(_)))
+        ));
+    }
+
+    #[test]
+    fn accepts_words_and_commas() {
+        let node = client("client-1", "eu-central-1a", "spot-1");
+        assert!(select("role=client and not zone=eu-central-1b").matches(&node));
+        assert!(select("role=core or type=c5.2xlarge").matches(&node));
+        assert!(select("role=client, asg~=^spot").matches(&node));
+        assert!(!select("role=client, asg=spot").matches(&node));
+    }
+
+    #[test]
+    fn reads_quoted_values() {
+        let node = client("client 1", "eu-central-1a", "a|b");
+        assert!(select("name=\"client 1\"").matches(&node));
+        assert!(select("asg='a|b' & name!='client-1'").matches(&node));
+        assert!(matches!(
+            select("name='!=(&)'"),
+            Selector::Compare(Field::Name, Op::Eq(value)) if value == "!=(&)"
+        ));
+    }
+
+    #[test]
+    fn compares_ips_with_ranges() {
+        let node = BitteNode::test("i-core", "core-1", "10.0.0.10");
+        assert!(select("priv_ip=10.0.0.10").matches(&node));
+        assert!(select("priv_ip=10.0.0.0/24").matches(&node));
+        assert!(!select("priv_ip=10.0.1.0/24").matches(&node));
+        assert!(select("pub_ip!=10.0.0.0/8").matches(&node));
+    }
+
+    #[test]
+    fn points_at_errors() {
+        let (reason, column) = fail("zone=a & bogus=1");
+        assert!(reason.starts_with("invalid selector: unknown field 'bogus'"));
+        assert_eq!(column, 9);
+
+        let (reason, column) = fail("name=\"core");
+        assert_eq!(reason, "invalid selector: unterminated quote");
+        assert_eq!(column, 5);
+
+        let (reason, column) = fail("name~core");
+        assert_eq!(reason, "invalid selector: expected '~=' for a regex match");
+        assert_eq!(column, 4);
+
+        let (reason, column) = fail("zone=a & (role=core");
+        assert_eq!(reason, "invalid selector: unclosed '('");
+        assert_eq!(column, 9);
+
+        let (reason, column) = fail("zone=a)");
+        assert_eq!(reason, "invalid selector: unexpected ')'");
+        assert_eq!(column, 6);
+
+        let (reason, column) = fail("zone=");
+        assert_eq!(reason, "invalid selector: expected a value for 'zone'");
+        assert_eq!(column, 5);
+
+        let (reason, column) = fail("zone eu-central-1a");
+        assert_eq!(
+            reason,
+            "invalid selector: expected one of '=', '!=', '~=' or '!~' after 'zone'"
+        );
+        assert_eq!(column, 5);
+
+        let (reason, column) = fail("zone=a &");
+        assert_eq!(reason, "invalid selector: expected a field");
+        assert_eq!(column, 8);
+
+        let (reason, column) = fail("name~=core-[");
+        assert_eq!(reason, "invalid selector: invalid regex");
+        assert_eq!(column, 6);
+    }
+
+    #[test]
+    fn counts_columns_in_characters() {
+        let (_, column) = fail("name='ö' & bogus=1");
+        assert_eq!(column, 11);
+    }
+}
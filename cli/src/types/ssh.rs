@@ -0,0 +1,312 @@
+use super::{known_hosts::KnownHosts, BitteNode};
+use crate::utils::data_dir;
+use anyhow::{bail, Context, Result};
+use clap::{ArgEnum, ArgMatches};
+use enum_utils::FromStr;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// Options passed to every ssh connection unless overridden, since ssh uses
+/// the first value it is given for each option
+const DEFAULT_OPTIONS: [&str; 2] = ["ForwardX11=no", "StrictHostKeyChecking=accept-new"];
+
+/// What runs non-interactive commands on several nodes at once
+#[derive(Debug, Copy, Clone, PartialEq, FromStr, ArgEnum)]
+#[enumeration(case_insensitive)]
+pub enum SshBackend {
+    /// one `ssh` process per node
+    Openssh,
+    /// an ssh client built into bitte, with one connection per node
+    Native,
+}
+
+/// How to reach the nodes of a cluster over ssh
+///
+/// Settings are taken from the command line or environment first, then from
+/// the `[clusters.<name>.ssh]` and `[ssh]` tables of the config file, e.g.
+///
+/// ```toml
+/// [ssh]
+/// user = "root"
+///
+/// [clusters.testnet.ssh]
+/// identity = "/home/me/testnet/secrets/ssh-testnet"
+/// port = 2222
+/// options = ["ServerAliveInterval=30"]
+/// ```
+#[derive(Debug, Clone)]
+pub struct SshConfig {
+    pub user: String,
+    pub port: u16,
+    /// the private key to authenticate with, `None` when relying on ssh-agent
+    /// or the user's own ssh configuration
+    pub identity: Option<PathBuf>,
+    /// extra `-o` options, taking precedence over the defaults
+    pub options: Vec<String>,
+    /// the cluster's own known_hosts file, keyed by instance id
+    pub known_hosts: Option<PathBuf>,
+    /// how long to wait for a node to accept a connection, set per command
+    pub connect_timeout: Option<Duration>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ConfigFile {
+    ssh: SshSection,
+    clusters: HashMap<String, ClusterSection>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+struct ClusterSection {
+    ssh: SshSection,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct SshSection {
+    user: Option<String>,
+    port: Option<u16>,
+    identity: Option<PathBuf>,
+    agent: Option<bool>,
+    options: Option<Vec<String>>,
+}
+
+impl SshSection {
+    /// Fills in the settings missing from `self` with those of `other`; a key
+    /// file or agent chosen in `self` replaces both of those in `other`
+    fn or(self, other: Self) -> Self {
+        let (identity, agent) = if self.identity.is_some() || self.agent.is_some() {
+            (self.identity, self.agent)
+        } else {
+            (other.identity, other.agent)
+        };
+
+        Self {
+            user: self.user.or(other.user),
+            port: self.port.or(other.port),
+            identity,
+            agent,
+            options: self.options.or(other.options),
+        }
+    }
+}
+
+impl Default for SshConfig {
+    fn default() -> Self {
+        Self {
+            user: "root".to_owned(),
+            port: 22,
+            identity: None,
+            options: Vec::new(),
+            known_hosts: None,
+            connect_timeout: None,
+        }
+    }
+}
+
+impl SshConfig {
+    pub fn new(args: &ArgMatches, cluster: &str) -> Result<Self> {
+        let mut file = ConfigFile::load(args.value_of("config"))?;
+        let from_file = match file.clusters.remove(cluster) {
+            Some(section) => section.ssh.or(file.ssh),
+            None => file.ssh,
+        };
+
+        let from_args = SshSection {
+            user: args.value_of_t("ssh-user").ok(),
+            port: args.value_of_t("ssh-port").ok(),
+            identity: args.value_of("ssh-identity").map(PathBuf::from),
+            agent: if args.is_present("ssh-agent") {
+                Some(true)
+            } else {
+                None
+            },
+            options: args
+                .values_of("ssh-option")
+                .map(|values| values.map(str::to_owned).collect()),
+        };
+
+        let settings = from_args.or(from_file);
+        let default = Self::default();
+
+        let identity = if settings.agent.unwrap_or_default() {
+            if env::var_os("SSH_AUTH_SOCK").is_none() {
+                bail!("using ssh-agent was requested, but SSH_AUTH_SOCK is not set");
+            }
+            None
+        } else {
+            match settings.identity {
+                Some(path) => {
+                    let path = expand_home(&path);
+                    let path = path
+                        .canonicalize()
+                        .with_context(|| format!("ssh identity {} not found", path.display()))?;
+                    Some(path)
+                }
+                None => find_cluster_key(cluster),
+            }
+        };
+
+        Ok(Self {
+            user: settings.user.unwrap_or(default.user),
+            port: settings.port.unwrap_or(default.port),
+            identity,
+            options: settings.options.unwrap_or(default.options),
+            known_hosts: known_hosts_path(cluster),
+            connect_timeout: None,
+        })
+    }
+
+    /// The `user@ip` to connect to
+    pub fn destination(&self, ip: IpAddr) -> String {
+        format!("{}@{}", self.user, ip)
+    }
+
+    /// The identity and `-o` options for ssh, excluding port and destination
+    pub fn options(&self) -> Vec<String> {
+        let mut args = Vec::new();
+
+        if let Some(identity) = &self.identity {
+            args.push("-i".to_owned());
+            args.push(identity.display().to_string());
+        }
+
+        if let Some(timeout) = self.connect_timeout {
+            args.push("-o".to_owned());
+            args.push(format!("ConnectTimeout={}", timeout.as_secs()));
+        }
+
+        for option in self.all_options() {
+            args.push("-o".to_owned());
+            args.push(option.to_owned());
+        }
+
+        args
+    }
+
+    /// The configured options followed by the defaults, as `Key=Value`
+    pub fn all_options(&self) -> impl Iterator<Item = &str> {
+        self.options
+            .iter()
+            .map(String::as_str)
+            .chain(DEFAULT_OPTIONS.iter().copied())
+    }
+
+    /// Options checking the host key of `node` against the cluster's
+    /// known_hosts file, under its instance id
+    pub fn host_key_options(&self, node: &BitteNode) -> Vec<String> {
+        match &self.known_hosts {
+            Some(path) => vec![
+                format!("HostKeyAlias={}", node.id),
+                format!("UserKnownHostsFile=\"{}\"", path.display()),
+                "HashKnownHosts=no".to_owned(),
+            ],
+            None => Vec::new(),
+        }
+    }
+
+    /// Like `host_key_options`, for tools such as deploy-rs that pass the same
+    /// options to ssh for every node; several nodes are checked by address,
+    /// against a copy of their keys written next to the known_hosts file
+    pub fn shared_host_key_options(&self, nodes: &[BitteNode]) -> Result<Vec<String>> {
+        let path = match (&self.known_hosts, nodes) {
+            (None, _) => return Ok(Vec::new()),
+            (Some(_), [node]) => return Ok(self.host_key_options(node)),
+            (Some(path), _) => path,
+        };
+
+        let mut by_address = path.as_os_str().to_owned();
+        by_address.push(".by-address");
+        let by_address = PathBuf::from(by_address);
+        KnownHosts::load(path)?.save_by_address(&by_address, nodes, self.port)?;
+
+        Ok(vec![
+            format!("UserKnownHostsFile=\"{}\"", by_address.display()),
+            "HashKnownHosts=no".to_owned(),
+        ])
+    }
+
+    /// All arguments for ssh to connect to `node`, ready for a remote command
+    pub fn args(&self, node: &BitteNode) -> Vec<String> {
+        let mut args = vec!["-p".to_owned(), self.port.to_string()];
+        args.extend(self.node_options(node));
+        args.push(self.destination(node.pub_ip));
+        args
+    }
+
+    /// The arguments for scp to reach `node`, ready for the files to copy
+    pub fn scp_args(&self, node: &BitteNode) -> Vec<String> {
+        let mut args = vec!["-P".to_owned(), self.port.to_string()];
+        args.extend(self.node_options(node));
+        args
+    }
+
+    /// `path` on `node`, as understood by scp
+    pub fn remote_path(&self, node: &BitteNode, path: &str) -> String {
+        format!("{}:{}", self.destination(node.pub_ip), path)
+    }
+
+    fn node_options(&self, node: &BitteNode) -> Vec<String> {
+        let mut args = self.options();
+        for option in self.host_key_options(node) {
+            args.push("-o".to_owned());
+            args.push(option);
+        }
+        args
+    }
+}
+
+impl ConfigFile {
+    /// Reads the config file at `path`, or the default location if that
+    /// exists; an explicitly given file has to exist
+    fn load(path: Option<&str>) -> Result<Self> {
+        let path = match path {
+            Some(path) => PathBuf::from(path),
+            None => match default_config_path() {
+                Some(path) if path.is_file() => path,
+                _ => return Ok(Self::default()),
+            },
+        };
+
+        let content = std::fs::read_to_string(&path)
+            .with_context(|| format!("failed to read config file {}", path.display()))?;
+        toml::from_str(&content)
+            .with_context(|| format!("failed to parse config file {}", path.display()))
+    }
+}
+
+/// `$XDG_CONFIG_HOME/bitte/config.toml`, falling back to `~/.config`
+fn default_config_path() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".config"),
+    };
+    Some(base.join("bitte").join("config.toml"))
+}
+
+/// `$XDG_DATA_HOME/bitte/known_hosts/<cluster>`, falling back to
+/// `~/.local/share`
+fn known_hosts_path(cluster: &str) -> Option<PathBuf> {
+    Some(data_dir()?.join("known_hosts").join(cluster))
+}
+
+fn expand_home(path: &Path) -> PathBuf {
+    match (path.strip_prefix("~"), env::var_os("HOME")) {
+        (Ok(rest), Some(home)) => PathBuf::from(home).join(rest),
+        _ => path.to_owned(),
+    }
+}
+
+/// Looks for `secrets/ssh-<cluster>` in the current directory and its
+/// parents, so commands work from anywhere inside the cluster's flake
+fn find_cluster_key(cluster: &str) -> Option<PathBuf> {
+    let cwd = env::current_dir().ok()?;
+    cwd.ancestors()
+        .map(|dir| dir.join("secrets").join(format!("ssh-{}", cluster)))
+        .find(|path| path.is_file())
+}
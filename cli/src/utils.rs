@@ -1,5 +1,17 @@
 pub mod nomad;
 
+use std::env;
+use std::path::PathBuf;
+
+/// `$XDG_DATA_HOME/bitte`, falling back to `~/.local/share/bitte`
+pub fn data_dir() -> Option<PathBuf> {
+    let base = match env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(env::var_os("HOME")?).join(".local/share"),
+    };
+    Some(base.join("bitte"))
+}
+
 #[derive(Clone)]
 pub struct Instance {
     pub public_ip: String,